@@ -1,10 +1,11 @@
-use std::path::PathBuf;
+use std::path::{Component, Path as StdPath, PathBuf};
 
-use axum::{extract::Path, routing::get, Json, Router};
+use axum::{body::Body, extract::Path, http::header, response::{IntoResponse, Response}, routing::get, Json, Router};
 use indexmap::IndexMap;
 use ring::digest::{digest, SHA256};
 use serde_json::Value;
-use tokio::{fs, io::AsyncReadExt as _};
+use tokio::fs;
+use tokio_util::io::ReaderStream;
 use walkdir::WalkDir;
 
 use crate::{api::errors::internal_and_log, ApiError, ApiResult, AppState, ASSETS_VAR};
@@ -24,12 +25,11 @@ async fn versions() -> ApiResult<Json<Value>> {
     let mut entries = fs::read_dir(dir_path).await.map_err(internal_and_log)?;
     
     while let Some(entry) = entries.next_entry().await.map_err(internal_and_log)? {
-        if entry.metadata().await.map_err(internal_and_log)?.is_dir() {
-            if let Some(name) = entry.file_name().to_str() {
-                let name = name.to_string();
-                if !name.starts_with('.') {
-                    directories.push(Value::String(name.to_string()));
-                }
+        if entry.metadata().await.map_err(internal_and_log)?.is_dir()
+            && let Some(name) = entry.file_name().to_str() {
+            let name = name.to_string();
+            if !name.starts_with('.') {
+                directories.push(Value::String(name.to_string()));
             }
         }
     }
@@ -42,19 +42,39 @@ async fn hashes(Path(version): Path<String>) -> ApiResult<Json<IndexMap<String,
     Ok(Json(map))
 }
 
-async fn download(Path((version, path)): Path<(String, String)>) -> ApiResult<Vec<u8>> {
-    let mut file = if let Ok(file) = fs::File::open(format!("{}/{version}/{path}", *ASSETS_VAR)).await {
+async fn download(Path((version, path)): Path<(String, String)>) -> ApiResult<Response> {
+    let path = asset_path(&version, &path).ok_or(ApiError::NotFound)?;
+    let file = if let Ok(file) = fs::File::open(&path).await {
         file
     } else {
         return Err(ApiError::NotFound)
     };
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).await.map_err(internal_and_log)?;
-    Ok(buffer)
+    let metadata = file.metadata().await.map_err(internal_and_log)?;
+    if !metadata.is_file() {
+        return Err(ApiError::NotFound)
+    }
+    let len = metadata.len();
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    Ok((
+        [(header::CONTENT_TYPE, mime.to_string()), (header::CONTENT_LENGTH, len.to_string())],
+        Body::from_stream(ReaderStream::new(file)),
+    ).into_response())
 }
 
 // non web
 
+/// Path of the asset inside the assets folder. Segments are percent-decoded by the router,
+/// so anything except plain names (`..`, `/etc`, `C:`) could lead out of the folder.
+fn asset_path(version: &str, path: &str) -> Option<PathBuf> {
+    let is_plain = |segment: &str| {
+        !segment.is_empty() && StdPath::new(segment).components().all(|c| matches!(c, Component::Normal(_)))
+    };
+    if !is_plain(version) || StdPath::new(version).components().count() != 1 || !is_plain(path) {
+        return None;
+    }
+    Some(PathBuf::from(&*ASSETS_VAR).join(version).join(path))
+}
+
 async fn index_assets(version: &str) -> anyhow::Result<IndexMap<String, Value>> {
     let mut map = IndexMap::new();
     let version_path = PathBuf::from(&*ASSETS_VAR).join(version);
@@ -75,4 +95,23 @@ async fn index_assets(version: &str) -> anyhow::Result<IndexMap<String, Value>>
     }
 
     Ok(map)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asset_paths_stay_inside() {
+        let root = PathBuf::from(&*ASSETS_VAR);
+        assert_eq!(asset_path("0.1.5", "assets/icon.png"), Some(root.join("0.1.5").join("assets/icon.png")));
+        // `/api/assets/x/%2Fetc%2Fpasswd`
+        assert_eq!(asset_path("x", "/etc/passwd"), None);
+        assert_eq!(asset_path("/etc", "passwd"), None);
+        assert_eq!(asset_path("x", "../../etc/passwd"), None);
+        assert_eq!(asset_path("x", "assets/../../secret"), None);
+        assert_eq!(asset_path("..", "Config.toml"), None);
+        assert_eq!(asset_path("a/b", "c"), None);
+        assert_eq!(asset_path("", "c"), None);
+    }
+}
@@ -1,10 +1,12 @@
+use std::sync::Arc;
+
 use axum::{extract::{Query, State}, http::HeaderMap, response::{IntoResponse, Response}, routing::get, Router};
 use reqwest::{header::USER_AGENT, StatusCode};
 use ring::digest::{self, digest};
-use tracing::{error, info, instrument};
+use tracing::{info, instrument, warn};
 
-use crate::{auth::{has_joined, Userinfo}, utils::rand, AppState};
-use super::types::auth::*;
+use crate::{auth::{has_joined, new_session_token, ClientIp, JoinedProfile, Userinfo}, state::SessionPolicy, utils::rand, AppState, AUTH_UNKNOWN_SERVER_ID};
+use super::{types::auth::*, SessionMessage};
 
 pub fn router() -> Router<AppState> {
     Router::new()
@@ -28,13 +30,26 @@ async fn id(
 async fn verify(
     // Second stage of authentication
     Query(query): Query<Verify>,
+    ClientIp(ip): ClientIp,
     header: HeaderMap,
     State(state): State<AppState>,
 ) -> Response {
     let server_id = query.id.clone();
-    let nickname = state.user_manager.pending_remove(&server_id).unwrap().1; // TODO: Add error check
+    let ttl = std::time::Duration::from_secs(state.config.read().await.auth.pending_ttl);
+    // Replayed, expired or simply made up
+    let Some(nickname) = state.user_manager.pending_remove(&server_id, ttl) else {
+        AUTH_UNKNOWN_SERVER_ID.inc();
+        warn!("{ip} tried to verify unknown or expired serverId");
+        return (StatusCode::UNAUTHORIZED, "unknown or expired serverId".to_string()).into_response();
+    };
+    let (providers, auth_config) = {
+        let config = state.config.read().await;
+        (config.auth_providers.clone(), config.auth.clone())
+    };
     let userinfo = match has_joined(
-        state.config.read().await.auth_providers.clone(),
+        providers,
+        &auth_config,
+        Arc::clone(&state.providers_health),
         &server_id,
         &nickname
     ).await {
@@ -44,39 +59,134 @@ async fn verify(
             return (StatusCode::INTERNAL_SERVER_ERROR, "internal verify error".to_string()).into_response();
         },
     };
-    if let Some((uuid, auth_provider)) = userinfo {
+    if let Some(JoinedProfile { uuid, name, properties, provider: auth_provider }) = userinfo {
+        // The client can ask for any name in /auth/id, only the provider knows the real one
+        let nickname = match name {
+            Some(name) if name != nickname => {
+                warn!("{nickname} was confirmed by {} as {name}", auth_provider.name);
+                name
+            },
+            Some(name) => name,
+            None => nickname,
+        };
         let umanager = state.user_manager;
+        if let Some(old) = umanager.rename(&uuid, &nickname) {
+            info!("{old} is now known as {nickname}");
+        }
+        if let Some(known) = umanager.get_by_uuid(&uuid)
+            && !known.auth_provider.is_empty() && known.auth_provider.name != auth_provider.name {
+            warn!("Auth provider conflict! {uuid} was authenticated by {} before, now by {} as {nickname}", known.auth_provider.name, auth_provider.name);
+        }
         if umanager.is_banned(&uuid) {
             info!("{nickname} tried to log in, but was banned");
             return (StatusCode::BAD_REQUEST, "You're banned!".to_string()).into_response();
         }
+        let (whitelist, ops_rank) = {
+            let config = state.config.read().await;
+            (config.mc_whitelist, config.mc_ops_rank.clone())
+        };
+        if whitelist && !state.minecraft.is_whitelisted(&uuid) {
+            info!("{nickname} tried to log in, but isn't whitelisted");
+            return (StatusCode::BAD_REQUEST, "You're not whitelisted!".to_string()).into_response();
+        }
+        // The serverId was shown to the auth providers, so it must not become a credential
+        let token = new_session_token();
+        let lifetime = std::time::Duration::from_secs(state.config.read().await.auth.token_lifetime);
         let mut userinfo = Userinfo {
             nickname,
             uuid,
-            token: Some(server_id.clone()),
+            token: Some(token.clone()),
             auth_provider,
+            properties,
             ..Default::default()
         };
-        if let Some(agent) = header.get(USER_AGENT) {
-            if let Ok(agent) = agent.to_str() {
-                userinfo.version = agent.to_string();
-            }
+        if let Some(rank) = ops_rank.filter(|_| state.minecraft.is_op(&uuid)) {
+            userinfo.rank = rank;
+        }
+        if let Some(agent) = header.get(USER_AGENT)
+            && let Ok(agent) = agent.to_str() {
+            userinfo.version = agent.to_string();
         }
         info!("{} logged in using {} with {}", userinfo.nickname, userinfo.auth_provider.name, userinfo.version);
 
-        match umanager.insert(uuid, server_id.clone(), userinfo.clone()) {
-            Ok(_) => {},
-            Err(_) => {
+        let policy = state.config.read().await.auth.session_policy;
+        match policy {
+            // A token is enough to connect, so it counts as a session even before the connection is made
+            SessionPolicy::Reject if state.session.contains(&uuid) || umanager.has_live_token(&uuid) => {
+                info!("{} already has a session, rejected", userinfo.nickname);
+                return (StatusCode::BAD_REQUEST, "second session detected".to_string()).into_response();
+            },
+            SessionPolicy::Replace => {
                 umanager.remove(&uuid);
-                if umanager.insert(uuid, server_id.clone(), userinfo).is_err() {
-                    error!("Old token error after attempting to remove it! Unexpected behavior!");
-                    return (StatusCode::BAD_REQUEST, "second session detected".to_string()).into_response();
-                };
-            }
+                // The replaced connections close once the senders are dropped here
+                for tx in state.session.take(&uuid) {
+                    if let Err(e) = tx.send(SessionMessage::Replaced) {
+                        warn!("Can't notify the replaced session of {uuid}: {e}");
+                    }
+                }
+            },
+            _ => {},
         }
-        (StatusCode::OK, server_id.to_string()).into_response()
+        umanager.insert(uuid, token.clone(), userinfo, lifetime);
+        (StatusCode::OK, token).into_response()
     } else {
         info!("failed to verify {nickname}");
         (StatusCode::BAD_REQUEST, "failed to verify".to_string()).into_response()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AuthProvider, AuthProviderKind, AuthProviders};
+
+    async fn verify_id(state: &AppState, id: &str) -> Response {
+        let query = Verify { id: id.to_string() };
+        verify(Query(query), ClientIp([127, 0, 0, 1].into()), HeaderMap::new(), State(state.clone())).await
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_server_id() {
+        let state = AppState::for_tests();
+
+        let forged = verify_id(&state, "0123456789abcdef0123456789abcdef01234567").await;
+        assert_eq!(forged.status(), StatusCode::UNAUTHORIZED);
+
+        // The first verification consumes the serverId
+        state.config.write().await.auth_providers = AuthProviders(vec![AuthProvider {
+            name: "LAN".to_string(),
+            kind: AuthProviderKind::Offline,
+            ..Default::default()
+        }]);
+        state.user_manager.pending_insert("replayed".to_string(), "Alice".to_string());
+        let verified = verify_id(&state, "replayed").await;
+        assert_eq!(verified.status(), StatusCode::OK);
+        let replayed = verify_id(&state, "replayed").await;
+        assert_eq!(replayed.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn reject_policy_counts_unused_tokens() {
+        let state = AppState::for_tests();
+        {
+            let mut config = state.config.write().await;
+            config.auth.session_policy = SessionPolicy::Reject;
+            config.auth_providers = AuthProviders(vec![AuthProvider {
+                name: "LAN".to_string(),
+                kind: AuthProviderKind::Offline,
+                ..Default::default()
+            }]);
+        }
+        state.user_manager.pending_insert("first".to_string(), "Alice".to_string());
+        assert_eq!(verify_id(&state, "first").await.status(), StatusCode::OK);
+
+        // Not connected yet, but the first token is still valid
+        state.user_manager.pending_insert("second".to_string(), "Alice".to_string());
+        assert_eq!(verify_id(&state, "second").await.status(), StatusCode::BAD_REQUEST);
+
+        // Once it is gone, logging in is fine again
+        state.user_manager.remove(&crate::auth::offline_uuid("Alice"));
+        state.user_manager.pending_insert("third".to_string(), "Alice".to_string());
+        assert_eq!(verify_id(&state, "third").await.status(), StatusCode::OK);
+    }
+}
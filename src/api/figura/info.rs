@@ -1,9 +1,10 @@
 use axum::{extract::State, Json};
 use serde_json::{json, Value};
 use tracing::error;
+use uuid::Uuid;
 
 use crate::{
-    utils::{get_figura_versions, get_motd, FiguraVersions}, AppState, FIGURA_DEFAULT_VERSION
+    auth::{Token, Userinfo}, utils::{get_figura_versions, get_motd, FiguraVersions}, AppState, FIGURA_DEFAULT_VERSION
 };
 
 pub async fn version(State(state): State<AppState>) -> Json<FiguraVersions> {
@@ -30,19 +31,23 @@ pub async fn motd(State(state): State<AppState>) -> Json<Vec<crate::utils::Motd>
     Json(get_motd(state).await)
 }
 
-pub async fn limits(State(state): State<AppState>) -> Json<Value> {
-    let state = &state.config.read().await.limitations;
+pub async fn limits(token: Option<Token>, State(state): State<AppState>) -> Json<Value> {
+    let config = state.config.read().await;
+    let limits = match token.and_then(|Token(token)| state.user_manager.get(&token).map(|user| user.clone())) {
+        Some(user) => config.limits_for(&user.uuid, &user.rank),
+        None => config.limits_for(&Uuid::nil(), &Userinfo::default().rank),
+    };
     Json(json!({
         "rate": {
-            "pingSize": 1024,
-            "pingRate": 32,
+            "pingSize": limits.ping_size,
+            "pingRate": limits.ping_rate,
             "equip": 1,
             "download": 50,
             "upload": 1
         },
         "limits": {
-            "maxAvatarSize": state.max_avatar_size * 1000,
-            "maxAvatars": state.max_avatars,
+            "maxAvatarSize": limits.max_avatar_size,
+            "maxAvatars": limits.max_avatars,
             "allowedBadges": {
                 "special": [0,0,0,0,0,0],
                 "pride": [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
@@ -5,4 +5,4 @@ pub mod profile;
 pub mod info;
 pub mod assets;
 
-pub use websocket::{initial as ws, SessionMessage};
\ No newline at end of file
+pub use websocket::{initial as ws, SessionMessage, Sessions, Subscriptions};
\ No newline at end of file
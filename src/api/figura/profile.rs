@@ -1,20 +1,17 @@
 use axum::{
-    body::Bytes, extract::{Path, State}, Json
+    body::{Body, Bytes}, extract::{Path, State}, http::{header, HeaderMap, StatusCode}, response::{IntoResponse, Response}, Json
 };
 use tracing::debug;
 use serde_json::{json, Value};
-use tokio::{
-    fs,
-    io::{self, AsyncReadExt, BufWriter},
-};
 use uuid::Uuid;
 
 use crate::{
-    api::errors::internal_and_log,
-    auth::Token, utils::{calculate_file_sha256, format_uuid},
-    ApiError, ApiResult, AppState, AVATARS_VAR
+    api::errors::{error_and_log, internal_and_log},
+    moon,
+    auth::Token, state::UserLimits, storage::{AvatarKey, DEFAULT_AVATAR_ID}, utils::{etag_matches, format_uuid},
+    ApiError, ApiResult, AppState
 };
-use super::websocket::S2CMessage;
+use super::{types::profile::Equip, websocket::S2CMessage};
 
 pub async fn user_info(
     Path(uuid): Path<Uuid>,
@@ -24,16 +21,24 @@ pub async fn user_info(
 
     let formatted_uuid = format_uuid(&uuid);
 
-    let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, formatted_uuid);
-
     let userinfo = if let Some(info) = state.user_manager.get_by_uuid(&uuid) { info } else {
         return Err(ApiError::BadRequest) // NOTE: Not Found (404) shows badge
     };
 
+    let owned = state.avatars.owned(&uuid);
+    let equipped: Vec<Value> = userinfo.equipped
+        .iter()
+        .filter_map(|id| owned.get(id).map(|hash| json!({
+            "id": id,
+            "owner": &formatted_uuid,
+            "hash": hash
+        })))
+        .collect();
+
     let mut user_info_response = json!({
         "uuid": &formatted_uuid,
         "rank": userinfo.rank,
-        "equipped": [],
+        "equipped": equipped,
         "lastUsed": userinfo.last_used,
         "equippedBadges": {
             "special": [0,0,0,0,0,0],
@@ -57,94 +62,187 @@ pub async fn user_info(
             .unwrap(),
         )
     }
-
-    if fs::metadata(&avatar_file).await.is_ok() {
-        if let Some(equipped) = user_info_response
-            .get_mut("equipped")
-            .and_then(Value::as_array_mut)
-        {
-            match calculate_file_sha256(&avatar_file) {
-                Ok(hash) => equipped.push(json!({
-                    "id": "avatar",
-                    "owner": &formatted_uuid,
-                    "hash": hash
-                })),
-                Err(_e) => {}
-            }
-        }
-    }
     Ok(Json(user_info_response))
 }
 
-pub async fn download_avatar(Path(uuid): Path<Uuid>) -> ApiResult<Vec<u8>> {
-    let uuid = format_uuid(&uuid);
-    tracing::info!("Requesting an avatar: {}", uuid);
-    let mut file = if let Ok(file) = fs::File::open(format!("{}/{}.moon", *AVATARS_VAR, uuid)).await {
-        file
-    } else {
-        return Err(ApiError::NotFound)
-    };
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).await.map_err(internal_and_log)?;
-    Ok(buffer)
+pub async fn download_avatar(
+    Path((uuid, id)): Path<(Uuid, String)>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let key = AvatarKey::new(uuid, avatar_id(Some(id))?);
+    tracing::info!("Requesting an avatar: {}", key);
+
+    // The hash changes with every upload, so it's a perfect entity tag
+    let etag = state.avatars.hash(&key).map(|hash| format!("\"{hash}\""));
+    if let Some(etag) = &etag
+        && etag_matches(&headers, etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, String::from("no-cache"))],
+        ).into_response());
+    }
+
+    let avatar = state.avatars.open(&key).await.map_err(internal_and_log)?.ok_or(ApiError::NotFound)?;
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, avatar.len)
+        .header(header::CACHE_CONTROL, "no-cache");
+    if let Some(etag) = etag {
+        response = response.header(header::ETAG, etag);
+    }
+    response.body(avatar.body).map_err(internal_and_log)
 }
 
 pub async fn upload_avatar(
+    id: Option<Path<String>>,
     Token(token): Token,
     State(state): State<AppState>,
-    body: Bytes,
+    headers: HeaderMap,
+    body: Body,
 ) -> ApiResult<String> {
-    let request_data = body;
+    let id = avatar_id(id.map(|Path(id)| id))?;
 
-    if let Some(user_info) = state.user_manager.get(&token) {
+    if let Some(user_info) = state.user_manager.get(&token).map(|user| user.clone()) {
         tracing::info!(
-            "{} ({}) trying to upload an avatar",
+            "{} ({}) trying to upload an avatar to slot {}",
             user_info.uuid,
-            user_info.nickname
+            user_info.nickname,
+            id
         );
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        let mut file = BufWriter::new(fs::File::create(&avatar_file).await.map_err(internal_and_log)?);
-        io::copy(&mut request_data.as_ref(), &mut file).await.map_err(internal_and_log)?;
+        let key = AvatarKey::new(user_info.uuid, id);
+
+        let limits = state.config.read().await.limits_for(&user_info.uuid, &user_info.rank);
+        // Replacing existing avatars is always allowed
+        let owned = state.avatars.owned(&key.owner);
+        if !owned.contains_key(&key.id) && owned.len() as u64 >= limits.max_avatars {
+            return Err(error_and_log(
+                format!("{} already has {} avatars", user_info.nickname, owned.len()),
+                ApiError::UnprocessableEntity(format!("avatar limit reached ({})", limits.max_avatars))
+            ))
+        }
+
+        let request_data = read_avatar(&headers, body, &limits).await?;
+        validate_avatar(&request_data, &limits).await?;
+        // The check above is only a shortcut, parallel uploads are caught here
+        if !state.avatars.put(&key, request_data, Some(limits.max_avatars)).await.map_err(internal_and_log)? {
+            return Err(error_and_log(
+                format!("{} exceeded the avatar limit with parallel uploads", user_info.nickname),
+                ApiError::UnprocessableEntity(format!("avatar limit reached ({})", limits.max_avatars))
+            ))
+        }
     }
     Ok("ok".to_string())
 }
 
-pub async fn equip_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<&'static str> {
+pub async fn equip_avatar(Token(token): Token, State(state): State<AppState>, body: Bytes) -> ApiResult<&'static str> {
     debug!("[API] S2C : Equip");
     let uuid = state.user_manager.get(&token).ok_or(ApiError::Unauthorized)?.uuid;
+
+    // Old clients don't send anything, they just want to notify subscribers
+    if !body.is_empty() {
+        let request: Vec<Equip> = serde_json::from_slice(&body).map_err(|e| error_and_log(e, ApiError::BadRequest))?;
+        let owned = state.avatars.owned(&uuid);
+        let mut equipped: Vec<String> = Vec::new();
+        for avatar in request {
+            if avatar.owner.is_some_and(|owner| owner != uuid) {
+                return Err(ApiError::UnprocessableEntity("only own avatars can be equipped".to_string()))
+            }
+            if !owned.contains_key(&avatar.id) {
+                return Err(ApiError::NotFound)
+            }
+            if !equipped.contains(&avatar.id) {
+                equipped.push(avatar.id);
+            }
+        }
+        state.user_manager.set_equipped(&uuid, equipped);
+    }
+
     send_event(&state, &uuid).await;
     Ok("ok")
 }
 
-pub async fn delete_avatar(Token(token): Token, State(state): State<AppState>) -> ApiResult<String> {
-    if let Some(user_info) = state.user_manager.get(&token) {
+pub async fn delete_avatar(id: Option<Path<String>>, Token(token): Token, State(state): State<AppState>) -> ApiResult<String> {
+    let id = avatar_id(id.map(|Path(id)| id))?;
+    if let Some(user_info) = state.user_manager.get(&token).map(|user| user.clone()) {
         tracing::info!(
-            "{} ({}) is trying to delete the avatar",
+            "{} ({}) is trying to delete the avatar from slot {}",
             user_info.uuid,
-            user_info.nickname
+            user_info.nickname,
+            id
         );
-        let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, user_info.uuid);
-        fs::remove_file(avatar_file).await.map_err(internal_and_log)?;
+        if !state.avatars.delete(&AvatarKey::new(user_info.uuid, id)).await.map_err(internal_and_log)? {
+            return Err(ApiError::NotFound)
+        }
         send_event(&state, &user_info.uuid).await;
     }
     Ok("ok".to_string())
 }
 
+/// Requested slot or the default one
+pub fn avatar_id(id: Option<String>) -> ApiResult<String> {
+    match id {
+        Some(id) if AvatarKey::is_valid_id(&id) => Ok(id),
+        Some(id) => Err(error_and_log(format!("invalid avatar id: {id}"), ApiError::BadRequest)),
+        None => Ok(DEFAULT_AVATAR_ID.to_string()),
+    }
+}
+
+/// Reads the upload body, but no more than the user's quota allows
+pub async fn read_avatar(headers: &HeaderMap, body: Body, limits: &UserLimits) -> ApiResult<Bytes> {
+    let too_large = || error_and_log(
+        "upload exceeds the quota",
+        ApiError::PayloadTooLarge(format!("avatar is larger than {} KB", limits.max_avatar_size / 1024))
+    );
+    // Don't bother reading the body if the client is honest about its size
+    let declared = headers.get(header::CONTENT_LENGTH).and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limits.max_avatar_size) {
+        return Err(too_large());
+    }
+    axum::body::to_bytes(body, limits.max_avatar_size as usize).await.map_err(|e| {
+        if e.into_inner().is::<http_body_util::LengthLimitError>() {
+            too_large()
+        } else {
+            error_and_log("upload was interrupted", ApiError::BadRequest)
+        }
+    })
+}
+
+/// Rejects anything that isn't a well-formed `.moon`
+pub async fn validate_avatar(data: &Bytes, limits: &UserLimits) -> ApiResult<()> {
+    let data = data.clone();
+    let max_uncompressed = limits.max_uncompressed_size;
+    tokio::task::spawn_blocking(move || moon::decode(&data, max_uncompressed))
+        .await
+        .map_err(internal_and_log)?
+        .map(|_| ())
+        .map_err(|e| error_and_log(&e, ApiError::UnprocessableEntity(format!("invalid avatar: {e}"))))
+}
+
 pub async fn send_event(state: &AppState, uuid: &Uuid) {
-    // To user subscribers
-    if let Some(broadcast) = state.subscribes.get(uuid) {
-        if broadcast.send(S2CMessage::Event(*uuid).into()).is_err() {
-            debug!("[WebSocket] Failed to send Event! There is no one to send. UUID: {uuid}")
-        };
-    } else {
-        debug!("[WebSocket] Failed to send Event! Can't find UUID: {uuid}")
+    let event = Bytes::from(Vec::<u8>::from(S2CMessage::Event(*uuid)));
+    // To user subscribers, unlike pings events are never skipped
+    if state.subscriptions.notify(uuid, event.clone()) == 0 {
+        debug!("[WebSocket] Failed to send Event! There is no one to send. UUID: {uuid}")
     };
     // To user
-    if let Some(session) = state.session.get(uuid) {
-        if session.send(super::SessionMessage::Ping(S2CMessage::Event(*uuid).into())).await.is_err() {
-            debug!("[WebSocket] Failed to send Event! WS doesn't connected? UUID: {uuid}")
-        };
-    } else {
-        debug!("[WebSocket] Failed to send Event! Can't find UUID: {uuid}")
+    if !state.session.send(uuid, super::SessionMessage::Ping(event)) {
+        debug!("[WebSocket] Failed to send Event! Can't find UUID or WS doesn't connected: {uuid}")
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_avatar_quota() {
+        let limits = UserLimits { max_avatar_size: 4, max_uncompressed_size: 64, max_avatars: 1, ping_size: 1024, ping_rate: 32, max_ping_violations: None };
+        let headers = HeaderMap::new();
+        assert_eq!(read_avatar(&headers, Body::from("moon"), &limits).await.unwrap(), "moon");
+        assert!(matches!(read_avatar(&headers, Body::from("moons"), &limits).await, Err(ApiError::PayloadTooLarge(_))));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_LENGTH, 5.into());
+        assert!(matches!(read_avatar(&headers, Body::empty(), &limits).await, Err(ApiError::PayloadTooLarge(_))));
+    }
+}
@@ -1 +1,2 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod profile;
\ No newline at end of file
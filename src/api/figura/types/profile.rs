@@ -0,0 +1,8 @@
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct Equip {
+    pub owner: Option<Uuid>,
+    pub id: String,
+}
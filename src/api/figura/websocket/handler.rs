@@ -1,12 +1,13 @@
 use anyhow::bail;
 use axum::{body::Bytes, extract::{ws::{Message, WebSocket}, State}};
-use dashmap::DashMap;
-use tokio::sync::{broadcast, mpsc};
+use std::{collections::HashSet, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use tokio::sync::mpsc;
 use tracing::instrument;
 
-use crate::{auth::Userinfo, AppState};
+use crate::{auth::Userinfo, AppState, SESSION_LAGGED_PINGS};
 
-use super::{AuthModeError, C2SMessage, RADError, RecvAndDecode, S2CMessage, SessionMessage, WSSession};
+use super::{decode_message, AuthModeError, C2SMessage, Liveness, LivenessEvent, PingLimiter, PingVerdict, RADError, RecvAndDecode, S2CMessage, SessionMessage, SubscribeResult, WSSession};
 
 pub async fn initial(
     ws: axum::extract::WebSocketUpgrade,
@@ -16,68 +17,127 @@ pub async fn initial(
 }
 
 async fn handle_socket(mut ws: WebSocket, state: AppState) {
+    let ws_config = state.config.read().await.websocket.clone();
     // Trying authenticate & get user data or dropping connection
-    match authenticate(&mut ws, &state).await {
-        Ok(user) => {
+    let auth = match ws_config.idle() {
+        Some(idle) => tokio::time::timeout(idle, authenticate(&mut ws, &state)).await.unwrap_or(Err(AuthModeError::Timeout)),
+        None => authenticate(&mut ws, &state).await,
+    };
+    match auth {
+        Ok((user, token, expires)) => {
+
+            let ping_limiter = PingLimiter::new(&state.config.read().await.limits_for(&user.uuid, &user.rank));
 
-            // Creating session & creating/getting channels
+            // Creating session
             let mut session = {
-                let sub_workers_aborthandles = DashMap::new();
-                
-                // Channel for receiving messages from internal functions.
-                let (own_tx, own_rx) = mpsc::channel(32);
-                state.session.insert(user.uuid, own_tx.clone());
-
-                // Channel for sending messages to subscribers
-                let subs_tx = match state.subscribes.get(&user.uuid) {
-                    Some(tx) => tx.clone(),
-                    None => {
-                        tracing::debug!("[Subscribes] Can't find own subs channel for {}, creating new...", user.uuid);
-                        let (subs_tx, _) = broadcast::channel(32);
-                        state.subscribes.insert(user.uuid, subs_tx.clone());
-                        subs_tx
-                    },
-                };
+                // Messages from internal functions, the connection ends once the sender is removed from sessions
+                let (control_tx, control_rx) = mpsc::unbounded_channel();
+                let control = control_tx.downgrade();
+                let id = state.session.insert(user.uuid, control_tx);
+                // Pings of subscribed players
+                let (pings_tx, pings_rx) = mpsc::channel(ws_config.ping_queue());
 
-                WSSession { user: user.clone(), own_tx, own_rx, subs_tx, sub_workers_aborthandles }
+                let lagged = Arc::new(AtomicU64::new(0));
+                WSSession {
+                    id, user: user.clone(), control, control_rx, pings_tx, pings_rx,
+                    subscriptions: HashSet::new(), token, expires, ping_limiter, lagged
+                }
             };
 
             // Starting main worker
-            if let Err(kind) = main_worker(&mut session, &mut ws, &state).await {
-                tracing::info!(error = %kind, nickname = %session.user.nickname, "Main worker exited");
-            }
-
-            for (_, handle) in session.sub_workers_aborthandles {
-                handle.abort();
+            match main_worker(&mut session, &mut ws, &state).await {
+                Ok(reason) => tracing::info!(%reason, nickname = %session.user.nickname, "Session closed"),
+                Err(kind) => tracing::info!(error = %kind, nickname = %session.user.nickname, "Main worker exited"),
             }
         
+            let lagged = session.lagged.load(Ordering::Relaxed);
+            SESSION_LAGGED_PINGS.observe(lagged as f64);
+            if lagged != 0 {
+                tracing::info!(nickname = %session.user.nickname, "Session couldn't keep up and skipped {lagged} pings");
+            }
+
             // Removing session data
-            state.session.remove(&user.uuid);
-            state.user_manager.remove(&user.uuid);
+            session.unsubscribe_all(&state.subscriptions);
+            state.session.remove(&user.uuid, session.id);
+            state.user_manager.remove_token(&session.token);
         },
         Err(kind) => {
             tracing::info!(error = %kind, "Can't authenticate");
         }
     }
 
-    // Closing connection
-    if let Err(kind) = ws.send(Message::Close(None)).await { tracing::trace!("[WebSocket] Closing fault: {}", kind) }
+    // Closing connection. The peer may be gone, so don't wait for it forever
+    if let Err(kind) = send(&mut ws, Message::Close(None), ws_config.pong()).await {
+        tracing::trace!("[WebSocket] Closing fault: {}", kind);
+    }
 }
 
 #[instrument(skip_all, fields(nickname = %session.user.nickname))]
-async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppState) -> anyhow::Result<()> {
+/// Returns the reason when the connection was closed by the client
+async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppState) -> anyhow::Result<String> {
     tracing::debug!("WebSocket control for {} is transferred to the main worker", session.user.nickname);
+    let ws_config = state.config.read().await.websocket.clone();
+    // A peer that stopped reading makes every send hang, so none of them may wait longer than for a pong
+    let wait = ws_config.pong();
+    let mut liveness = Liveness::new(&ws_config);
     loop {
         tokio::select! {
-            external_msg = ws.recv_and_decode() => {
-
+            // Control messages go first, so an event is never delivered after pings that were queued later
+            biased;
+            internal_msg = session.control_rx.recv() => {
+                let Some(internal_msg) = internal_msg else {
+                    bail!("{} was removed from sessions", session.user.nickname)
+                };
+                match internal_msg {
+                    SessionMessage::Ping(msg) => {
+                        send(ws, Message::Binary(msg), wait).await?
+                    },
+                    SessionMessage::Banned => {
+                        let _ = ban_action(ws, wait).await
+                            .inspect_err(
+                                |kind| tracing::warn!("[WebSocket] Didn't get the ban message due to {}", kind)
+                            );
+                        bail!("{} banned!", session.user.nickname)
+                    },
+                    SessionMessage::Replaced => {
+                        let _ = replaced_action(ws, wait).await
+                            .inspect_err(
+                                |kind| tracing::warn!("[WebSocket] Didn't get the replace message due to {}", kind)
+                            );
+                        bail!("{} logged in from another place", session.user.nickname)
+                    },
+                    SessionMessage::Kicked => {
+                        let _ = kicked_action(ws, wait).await
+                            .inspect_err(
+                                |kind| tracing::warn!("[WebSocket] Didn't get the kick message due to {}", kind)
+                            );
+                        bail!("{} kicked", session.user.nickname)
+                    },
+                }
+            },
+            external_msg = ws.recv() => {
                 // Getting a value or halt the worker without an error
+                let external_msg = match external_msg.ok_or(RADError::StreamClosed).and_then(|msg| Ok(msg?)) {
+                    // Pings from the client are answered automatically
+                    Ok(Message::Ping(_)) => continue,
+                    // Proves the client is alive, not that it's in use
+                    Ok(Message::Pong(_)) => {
+                        liveness.pong();
+                        continue;
+                    },
+                    Ok(msg) => {
+                        liveness.seen();
+                        decode_message(msg)
+                    },
+                    Err(kind) => Err(kind),
+                };
                 let external_msg = match external_msg {
                     Ok(m) => m,
                     Err(kind) => {
                         match kind {
-                            RADError::Close(_) => return Ok(()),
-                            RADError::StreamClosed => return Ok(()),
+                            RADError::Close(Some(frame)) => return Ok(format!("closed by client, {frame}")),
+                            RADError::Close(None) => return Ok(String::from("closed by client")),
+                            RADError::StreamClosed => return Ok(String::from("connection lost")),
                             _ => return Err(kind.into())
                         }
                     },
@@ -87,95 +147,93 @@ async fn main_worker(session: &mut WSSession, ws: &mut WebSocket, state: &AppSta
                 match external_msg {
                     C2SMessage::Token(_) => bail!("authentication passed, but the client sent the Token again"),
                     C2SMessage::Ping(func_id, echo, data) => {
-                        let s2c_ping: Vec<u8> = S2CMessage::Ping(session.user.uuid, func_id, echo, data).into();
+                        match session.ping_limiter.check(data.len()) {
+                            PingVerdict::Allow => (),
+                            PingVerdict::Warn => {
+                                tracing::info!("[WebSocket] {} exceeded ping limits, pings are dropped", session.user.nickname);
+                                let toast = S2CMessage::Toast(1, "Too many pings!".to_string(), Some("Some of them were dropped".to_string()));
+                                send(ws, Message::Binary(Into::<Vec<u8>>::into(toast).into()), wait).await?;
+                                continue;
+                            },
+                            PingVerdict::Drop => continue,
+                            PingVerdict::Disconnect => {
+                                let _ = ping_limit_action(ws, wait).await
+                                    .inspect_err(
+                                        |kind| tracing::warn!("[WebSocket] Didn't get the ping limit message due to {}", kind)
+                                    );
+                                bail!("{} kept exceeding ping limits", session.user.nickname)
+                            },
+                        }
+                        // Encoded once, every receiver shares the buffer
+                        let s2c_ping = Bytes::from(Vec::<u8>::from(S2CMessage::Ping(session.user.uuid, func_id, echo, data)));
                         
                         // Echo check
                         if echo {
-                            ws.send(Message::Binary(s2c_ping.clone().into())).await?
+                            send(ws, Message::Binary(s2c_ping.clone()), wait).await?
                         }
                         // Sending to others
-                        let _ = session.subs_tx.send(s2c_ping);
+                        state.subscriptions.publish(&session.user.uuid, s2c_ping);
                     },
                     C2SMessage::Sub(uuid) => {
                         tracing::debug!("[WebSocket] {} subscribes to {}", session.user.nickname, uuid);
                         
-                        // Doesn't allow to subscribe to yourself
-                        if session.user.uuid != uuid {
-                            // Creates a channel to send pings to a subscriber if it can't find an existing one
-                            let rx = match state.subscribes.get(&uuid) {
-                                Some(tx) => tx.subscribe(),
-                                None => {
-                                    let (tx, rx) = broadcast::channel(32);
-                                    state.subscribes.insert(uuid, tx);
-                                    rx
-                                },
-                            };
-                            let handle = tokio::spawn(sub_worker(session.own_tx.clone(), rx)).abort_handle();
-                            session.sub_workers_aborthandles.insert(uuid, handle);
+                        match session.subscribe(&state.subscriptions, uuid, ws_config.max_subscriptions) {
+                            SubscribeResult::Subscribed | SubscribeResult::Own => (),
+                            SubscribeResult::AlreadySubscribed => {
+                                tracing::debug!("[WebSocket] {} is already subscribed to {}", session.user.nickname, uuid);
+                            },
+                            SubscribeResult::LimitReached => {
+                                tracing::debug!("[WebSocket] {} reached the subscription limit, {} ignored", session.user.nickname, uuid);
+                            },
                         }
                     },
                     C2SMessage::Unsub(uuid) => {
                         tracing::debug!("[WebSocket] {} unsubscribes from {}", session.user.nickname, uuid);
 
-                        match session.sub_workers_aborthandles.get(&uuid) {
-                            Some(handle) => handle.abort(),
-                            None => tracing::warn!("[WebSocket] {} was not subscribed.", session.user.nickname),
-                        };
+                        if !session.unsubscribe(&state.subscriptions, &uuid) {
+                            tracing::trace!("[WebSocket] {} was not subscribed to {}", session.user.nickname, uuid);
+                        }
                     },
                 }
             },
-            internal_msg = session.own_rx.recv() => {
-                let internal_msg = internal_msg.ok_or(anyhow::anyhow!("Unexpected error! Session channel broken!"))?;
-                match internal_msg {
-                    SessionMessage::Ping(msg) => {
-                        ws.send(Message::Binary(msg.into())).await?
-                    },
-                    SessionMessage::Banned => {
-                        let _ = ban_action(ws).await
-                            .inspect_err(
-                                |kind| tracing::warn!("[WebSocket] Didn't get the ban message due to {}", kind)
-                            );
-                        bail!("{} banned!", session.user.nickname)
-                    },
-                }
-            }
-        }
-    }
-}
-
-async fn sub_worker(tx_main: mpsc::Sender<SessionMessage>, mut rx: broadcast::Receiver<Vec<u8>>) {
-    loop {
-        let msg = match rx.recv().await {
-            Ok(m) => m,
-            Err(kind) => {
-                tracing::error!("[Subscribes_Worker] Broadcast error! {}", kind);
-                return;
+            event = liveness.next() => match event {
+                LivenessEvent::Heartbeat => send(ws, Message::Ping(Bytes::new()), wait).await?,
+                LivenessEvent::PongTimeout => {
+                    timeout_action(ws, wait).await;
+                    bail!("no pong for {}s", ws_config.pong_timeout)
+                },
+                LivenessEvent::Idle => {
+                    timeout_action(ws, wait).await;
+                    bail!("idle for {}s", ws_config.idle_timeout)
+                },
+            },
+            () = tokio::time::sleep_until(session.expires.into()) => {
+                send(ws, Message::Close(Some(axum::extract::ws::CloseFrame { code: 4000, reason: "Re-auth".into() })), wait).await?;
+                bail!("session token expired")
             },
-        };
-        match tx_main.send(SessionMessage::Ping(msg)).await {
-            Ok(_) => (),
-            Err(kind) => {
-                tracing::error!("[Subscribes_Worker] Session error! {}", kind);
-                return;
+            // The session holds a sender, so the queue is never closed
+            Some(msg) = session.pings_rx.recv() => {
+                send(ws, Message::Binary(msg), wait).await?
             },
         }
     }
 }
 
-async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<Userinfo, AuthModeError> {
+async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<(Userinfo, String, std::time::Instant), AuthModeError> {
+    let wait = state.config.read().await.websocket.pong();
     match socket.recv_and_decode().await {
         Ok(msg) => {
             match msg {
                 C2SMessage::Token(token) => {
                     let token = String::from_utf8(token.to_vec()).map_err(|_| AuthModeError::ConvertError)?;
-                    match state.user_manager.get(&token) {
-                        Some(user) => {
-                            if socket.send(Message::Binary(Bytes::from(Into::<Vec<u8>>::into(S2CMessage::Auth)))).await.is_err() {
+                    match state.user_manager.get(&token).zip(state.user_manager.token_expiry(&token)) {
+                        Some((user, expires)) => {
+                            if send(socket, Message::Binary(Bytes::from(Into::<Vec<u8>>::into(S2CMessage::Auth))), wait).await.is_err() {
                                 Err(AuthModeError::SendError)
                             } else if !user.banned {
-                                Ok(user.clone())
+                                Ok((user.clone(), token.clone(), expires))
                             } else {
-                                let _ = ban_action(socket).await
+                                let _ = ban_action(socket, wait).await
                                     .inspect_err(
                                         |kind| tracing::warn!("[WebSocket] Didn't get the ban message due to {}", kind)
                                     );
@@ -183,8 +241,10 @@ async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<Userin
                             }
                         },
                         None => {
-                            if socket.send(
-                                Message::Close(Some(axum::extract::ws::CloseFrame { code: 4000, reason: "Re-auth".into() }))
+                            if send(
+                                socket,
+                                Message::Close(Some(axum::extract::ws::CloseFrame { code: 4000, reason: "Re-auth".into() })),
+                                wait
                             ).await.is_err() {
                                 Err(AuthModeError::SendError)
                             } else {
@@ -204,10 +264,35 @@ async fn authenticate(socket: &mut WebSocket, state: &AppState) -> Result<Userin
     }
 }
 
-async fn ban_action(ws: &mut WebSocket) -> anyhow::Result<()> {
-    ws.send(Message::Binary(Into::<Vec<u8>>::into(S2CMessage::Toast(2, "You're banned!".to_string(), None)).into())).await?;
-    tokio::time::sleep(std::time::Duration::from_secs(6)).await;
-    ws.send(Message::Close(Some(axum::extract::ws::CloseFrame { code: 4001, reason: "You're banned!".into() }))).await?;
-
+/// Sends the message, but gives up if the peer doesn't take it in time
+async fn send(ws: &mut WebSocket, msg: Message, wait: Duration) -> anyhow::Result<()> {
+    tokio::time::timeout(wait, ws.send(msg)).await.map_err(|_| anyhow::anyhow!("send timed out"))??;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Shows the toast, waits until it's read and closes the connection
+async fn close_with_toast(ws: &mut WebSocket, wait: Duration, toast: &str, code: u16, reason: &'static str) -> anyhow::Result<()> {
+    send(ws, Message::Binary(Into::<Vec<u8>>::into(S2CMessage::Toast(2, toast.to_string(), None)).into()), wait).await?;
+    tokio::time::sleep(Duration::from_secs(6)).await;
+    send(ws, Message::Close(Some(axum::extract::ws::CloseFrame { code, reason: reason.into() })), wait).await
+}
+
+async fn ban_action(ws: &mut WebSocket, wait: Duration) -> anyhow::Result<()> {
+    close_with_toast(ws, wait, "You're banned!", 4001, "You're banned!").await
+}
+
+async fn replaced_action(ws: &mut WebSocket, wait: Duration) -> anyhow::Result<()> {
+    close_with_toast(ws, wait, "Logged in from another place!", 4002, "Session replaced").await
+}
+
+async fn kicked_action(ws: &mut WebSocket, wait: Duration) -> anyhow::Result<()> {
+    close_with_toast(ws, wait, "You were kicked!", 4003, "Kicked").await
+}
+
+async fn ping_limit_action(ws: &mut WebSocket, wait: Duration) -> anyhow::Result<()> {
+    close_with_toast(ws, wait, "Too many pings!", 4004, "Ping limit exceeded").await
+}
+
+async fn timeout_action(ws: &mut WebSocket, wait: Duration) {
+    let _ = send(ws, Message::Close(Some(axum::extract::ws::CloseFrame { code: 4005, reason: "Timeout".into() })), wait).await;
+}
@@ -52,6 +52,8 @@ pub enum AuthModeError {
     AuthenticationFailure,
     #[error("{0} banned")]
     Banned(String),
+    #[error("no token in time")]
+    Timeout,
 }
 
 #[cfg(test)]
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+use crate::{state::UserLimits, utils::TokenBucket, PINGS_DISCONNECTS, PINGS_REJECTED};
+
+/// Violations are forgotten after this long without new ones, so only sustained abuse leads to a disconnect
+const VIOLATIONS_RESET_AFTER: Duration = Duration::from_secs(10);
+
+/// What to do with a ping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PingVerdict {
+    Allow,
+    /// First violation, the client is warned and the ping is dropped
+    Warn,
+    Drop,
+    /// Too many violations
+    Disconnect,
+}
+
+/// Enforces `pingRate` and `pingSize` advertised in /limits for a single connection
+#[derive(Debug, Clone)]
+pub struct PingLimiter {
+    max_size: f64,
+    pings: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    max_violations: Option<u32>,
+}
+
+impl PingLimiter {
+    /// Takes the limits of the user, see [`crate::state::Config::limits_for`]
+    pub fn new(limits: &UserLimits) -> Self {
+        Self {
+            max_size: limits.ping_size as f64,
+            pings: TokenBucket::new(limits.ping_rate as f64, limits.ping_rate as f64),
+            bytes: TokenBucket::new(limits.ping_size as f64, limits.ping_size as f64),
+            violations: 0,
+            last_violation: None,
+            max_violations: limits.max_ping_violations,
+        }
+    }
+
+    pub fn check(&mut self, size: usize) -> PingVerdict {
+        self.check_at(size, Instant::now())
+    }
+
+    fn check_at(&mut self, size: usize, now: Instant) -> PingVerdict {
+        let size = size as f64;
+        let reason = if size > self.max_size {
+            "size"
+        } else if self.pings.available(now) < 1.0 {
+            "rate"
+        } else if !self.bytes.try_take(size, now) {
+            "bandwidth"
+        } else {
+            self.pings.try_take(1.0, now);
+            return PingVerdict::Allow;
+        };
+
+        PINGS_REJECTED.with_label_values(&[reason]).inc();
+        if self.last_violation.is_some_and(|last| now.duration_since(last) >= VIOLATIONS_RESET_AFTER) {
+            self.violations = 0;
+        }
+        self.last_violation = Some(now);
+        self.violations += 1;
+        if self.max_violations.is_some_and(|max| self.violations > max) {
+            PINGS_DISCONNECTS.inc();
+            PingVerdict::Disconnect
+        } else if self.violations == 1 {
+            PingVerdict::Warn
+        } else {
+            PingVerdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(max_ping_violations: Option<u32>) -> PingLimiter {
+        PingLimiter::new(&UserLimits { max_avatar_size: 100, max_uncompressed_size: 1600, max_avatars: 10, ping_size: 100, ping_rate: 2, max_ping_violations })
+    }
+
+    #[test]
+    fn rate_and_size() {
+        let mut limiter = limiter(None);
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(101, now), PingVerdict::Warn);
+        assert_eq!(limiter.check_at(10, now), PingVerdict::Allow);
+        assert_eq!(limiter.check_at(80, now), PingVerdict::Allow);
+        assert_eq!(limiter.check_at(1, now), PingVerdict::Drop);
+        // One ping and 50 bytes are refilled
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.check_at(70, later), PingVerdict::Drop);
+        assert_eq!(limiter.check_at(50, later), PingVerdict::Allow);
+        assert_eq!(limiter.check_at(60, now + Duration::from_millis(1500)), PingVerdict::Allow);
+    }
+
+    #[test]
+    fn disconnects_offenders() {
+        let mut limiter = limiter(Some(2));
+        assert_eq!(limiter.check(1000), PingVerdict::Warn);
+        assert_eq!(limiter.check(1000), PingVerdict::Drop);
+        assert_eq!(limiter.check(1000), PingVerdict::Disconnect);
+    }
+
+    #[test]
+    fn violations_are_forgiven() {
+        let mut limiter = limiter(Some(2));
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(1000, now), PingVerdict::Warn);
+        assert_eq!(limiter.check_at(1000, now + Duration::from_secs(5)), PingVerdict::Drop);
+        // Quiet for a while, counting starts over
+        let later = now + Duration::from_secs(5) + VIOLATIONS_RESET_AFTER;
+        assert_eq!(limiter.check_at(1000, later), PingVerdict::Warn);
+        assert_eq!(limiter.check_at(1000, later), PingVerdict::Drop);
+        assert_eq!(limiter.check_at(1000, later), PingVerdict::Disconnect);
+    }
+}
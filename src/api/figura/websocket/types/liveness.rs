@@ -0,0 +1,123 @@
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::state::WebSocketConfig;
+
+/// What the connection has to do next to stay alive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LivenessEvent {
+    /// Time to ping the client
+    Heartbeat,
+    /// The client didn't answer the ping in time
+    PongTimeout,
+    /// Nothing was received from the client for too long
+    Idle,
+}
+
+/// Heartbeat, pong deadline and idle timeout of a single connection
+#[derive(Debug)]
+pub struct Liveness {
+    heartbeat: Option<Interval>,
+    pong_deadline: Option<Instant>,
+    last_seen: Instant,
+    config: WebSocketConfig,
+}
+
+impl Liveness {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        let heartbeat = config.heartbeat().map(|period| {
+            let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self { heartbeat, pong_deadline: None, last_seen: Instant::now(), config: config.clone() }
+    }
+
+    /// Any data from the client. Pongs only answer the heartbeat, the client may be connected but unused
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    pub fn pong(&mut self) {
+        self.pong_deadline = None;
+    }
+
+    /// Waits for the next event. Cancel safe, so it can be used in `select!`.
+    pub async fn next(&mut self) -> LivenessEvent {
+        let idle_deadline = self.config.idle().map(|idle| self.last_seen + idle);
+        tokio::select! {
+            () = tick(&mut self.heartbeat) => {
+                // Unanswered pings don't move the deadline
+                self.pong_deadline.get_or_insert(Instant::now() + self.config.pong());
+                LivenessEvent::Heartbeat
+            },
+            () = sleep_until(self.pong_deadline) => LivenessEvent::PongTimeout,
+            () = sleep_until(idle_deadline) => LivenessEvent::Idle,
+        }
+    }
+}
+
+/// Next heartbeat, never if it's disabled
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => { interval.tick().await; },
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config(heartbeat_interval: u64, idle_timeout: u64) -> WebSocketConfig {
+        WebSocketConfig { heartbeat_interval, pong_timeout: 15, idle_timeout, ..Default::default() }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pong_deadline() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(&config(30, 0));
+
+        assert_eq!(liveness.next().await, LivenessEvent::Heartbeat);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        liveness.pong();
+        assert_eq!(liveness.next().await, LivenessEvent::Heartbeat);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        // No pong this time
+        assert_eq!(liveness.next().await, LivenessEvent::PongTimeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(75));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(&config(0, 120));
+
+        tokio::time::sleep(Duration::from_secs(100)).await;
+        liveness.seen();
+        assert_eq!(liveness.next().await, LivenessEvent::Idle);
+        assert_eq!(start.elapsed(), Duration::from_secs(220));
+
+        // Answering the heartbeat doesn't keep an unused connection open
+        let start = Instant::now();
+        let mut liveness = Liveness::new(&config(30, 100));
+        for _ in 0..3 {
+            assert_eq!(liveness.next().await, LivenessEvent::Heartbeat);
+            liveness.pong();
+        }
+        assert_eq!(liveness.next().await, LivenessEvent::Idle);
+        assert_eq!(start.elapsed(), Duration::from_secs(100));
+
+        // Both disabled, nothing ever happens
+        let mut liveness = Liveness::new(&config(0, 0));
+        assert!(tokio::time::timeout(Duration::from_secs(3600), liveness.next()).await.is_err());
+    }
+}
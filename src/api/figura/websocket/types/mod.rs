@@ -2,10 +2,16 @@ mod c2s;
 mod s2c;
 mod errors;
 mod session;
+mod limiter;
+mod liveness;
+mod subscriptions;
 
 use std::time::Instant;
 
 pub use session::*;
+pub use limiter::*;
+pub use liveness::*;
+pub use subscriptions::*;
 pub use errors::*;
 pub use c2s::*;
 pub use s2c::*;
@@ -21,21 +27,25 @@ pub trait RecvAndDecode {
 impl RecvAndDecode for WebSocket {
     async fn recv_and_decode(&mut self) -> Result<C2SMessage, RADError> {
         let msg = self.recv().await.ok_or(RADError::StreamClosed)??;
-        
-        if let Message::Close(frame) = msg {
-            return Err(RADError::Close(frame.map(|f| format!("code: {}, reason: {}", f.code, f.reason))));
-        }
-
-        let start = Instant::now();
-        
-        let data = msg.into_data();
-        let msg = C2SMessage::try_from(data.as_ref())
-            .map_err(|e| { PINGS_ERROR.inc(); RADError::DecodeError(e, faster_hex::hex_string(&data)) });
-        
-        let latency = start.elapsed().as_secs_f64();
-        PINGS
-            .with_label_values(&[msg.as_ref().map(|m| m.name()).unwrap_or("error")])
-            .observe(latency);
-        msg
+        decode_message(msg)
     }
+}
+
+/// Decodes a received message, a close frame becomes [`RADError::Close`]
+pub fn decode_message(msg: Message) -> Result<C2SMessage, RADError> {
+    if let Message::Close(frame) = msg {
+        return Err(RADError::Close(frame.map(|f| format!("code: {}, reason: {}", f.code, f.reason))));
+    }
+
+    let start = Instant::now();
+    
+    let data = msg.into_data();
+    let msg = C2SMessage::try_from(data.as_ref())
+        .map_err(|e| { PINGS_ERROR.inc(); RADError::DecodeError(e, faster_hex::hex_string(&data)) });
+    
+    let latency = start.elapsed().as_secs_f64();
+    PINGS
+        .with_label_values(&[msg.as_ref().map(|m| m.name()).unwrap_or("error")])
+        .observe(latency);
+    msg
 }
\ No newline at end of file
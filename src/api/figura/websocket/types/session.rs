@@ -1,15 +1,215 @@
+use std::{collections::HashSet, sync::{atomic::{AtomicU64, Ordering}, Arc}};
+
+use axum::body::Bytes;
 use dashmap::DashMap;
-use tokio::{sync::{broadcast, mpsc}, task::AbortHandle};
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
 pub struct WSSession {
+    /// Id from [`Sessions::insert`]
+    pub id: u64,
     pub user: crate::auth::Userinfo,
-    pub own_tx: mpsc::Sender<SessionMessage>,
-    pub own_rx: mpsc::Receiver<SessionMessage>,
-    pub subs_tx: broadcast::Sender<Vec<u8>>,
-    pub sub_workers_aborthandles: DashMap<uuid::Uuid, AbortHandle>,
+    /// The only strong sender is kept by [`Sessions`], so the channel closes once the connection is removed from there
+    pub control: mpsc::WeakUnboundedSender<SessionMessage>,
+    pub control_rx: mpsc::UnboundedReceiver<SessionMessage>,
+    /// Pings of subscribed players, see [`super::Subscriptions::publish`]
+    pub pings_tx: mpsc::Sender<Bytes>,
+    pub pings_rx: mpsc::Receiver<Bytes>,
+    /// Players whose pings this connection receives
+    pub subscriptions: HashSet<Uuid>,
+    /// Token used to open this connection
+    pub token: String,
+    /// When the session token expires
+    pub expires: std::time::Instant,
+    pub ping_limiter: super::PingLimiter,
+    /// Pings skipped by subscriptions that couldn't keep up
+    pub lagged: Arc<AtomicU64>,
+}
+
+/// Outcome of [`WSSession::subscribe`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscribeResult {
+    Subscribed,
+    /// Subscribing to yourself is ignored
+    Own,
+    AlreadySubscribed,
+    /// The connection has `max` subscriptions already
+    LimitReached,
+}
+
+impl WSSession {
+    /// Starts receiving pings of the player, at most `max` players at once
+    pub fn subscribe(&mut self, subscriptions: &super::Subscriptions, uuid: Uuid, max: usize) -> SubscribeResult {
+        if self.user.uuid == uuid {
+            return SubscribeResult::Own;
+        }
+        if self.subscriptions.contains(&uuid) {
+            return SubscribeResult::AlreadySubscribed;
+        }
+        if self.subscriptions.len() >= max {
+            return SubscribeResult::LimitReached;
+        }
+        let subscriber = super::Subscriber {
+            session: self.id,
+            pings: self.pings_tx.clone(),
+            control: self.control.clone(),
+            lagged: Arc::clone(&self.lagged),
+        };
+        subscriptions.subscribe(uuid, subscriber);
+        self.subscriptions.insert(uuid);
+        SubscribeResult::Subscribed
+    }
+
+    /// Returns false if the connection wasn't subscribed, which is normal: clients unsubscribe from everyone who goes out of sight
+    pub fn unsubscribe(&mut self, subscriptions: &super::Subscriptions, uuid: &Uuid) -> bool {
+        self.subscriptions.remove(uuid) && subscriptions.unsubscribe(uuid, self.id)
+    }
+
+    /// Drops every subscription, called when the connection closes
+    pub fn unsubscribe_all(&mut self, subscriptions: &super::Subscriptions) {
+        for uuid in self.subscriptions.drain() {
+            subscriptions.unsubscribe(&uuid, self.id);
+        }
+    }
 }
 
+/// Messages that are never dropped, unlike pings of subscribed players
+#[derive(Debug, Clone)]
 pub enum SessionMessage {
-    Ping(Vec<u8>),
+    /// Encoded message, shared by all receivers
+    Ping(Bytes),
     Banned,
-}
\ No newline at end of file
+    /// The player logged in from another place
+    Replaced,
+    /// Disconnected by a moderator
+    Kicked,
+}
+
+/// Open WebSocket connections, a player can have several of them with `allow-multiple` session policy
+#[derive(Debug, Default)]
+pub struct Sessions {
+    connections: DashMap<Uuid, Vec<(u64, mpsc::UnboundedSender<SessionMessage>)>>,
+    next_id: AtomicU64,
+}
+
+impl Sessions {
+    /// Registers a connection, returns its id for [`Sessions::remove`]
+    pub fn insert(&self, uuid: Uuid, tx: mpsc::UnboundedSender<SessionMessage>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.entry(uuid).or_default().push((id, tx));
+        id
+    }
+    pub fn remove(&self, uuid: &Uuid, id: u64) {
+        self.connections.remove_if_mut(uuid, |_, connections| {
+            connections.retain(|(other, _)| *other != id);
+            connections.is_empty()
+        });
+    }
+    /// Forgets every connection of the player, returns their senders.
+    /// The connections end once the senders are dropped and the queued messages are handled.
+    pub fn take(&self, uuid: &Uuid) -> Vec<mpsc::UnboundedSender<SessionMessage>> {
+        self.connections.remove(uuid).map(|(_, connections)| connections.into_iter().map(|(_, tx)| tx).collect()).unwrap_or_default()
+    }
+    pub fn contains(&self, uuid: &Uuid) -> bool {
+        self.connections.contains_key(uuid)
+    }
+    /// Number of connected players
+    pub fn count(&self) -> usize {
+        self.connections.len()
+    }
+    /// Sends the message to every connection of the player, returns false if nobody received it
+    pub fn send(&self, uuid: &Uuid, msg: SessionMessage) -> bool {
+        self.connections.get(uuid).is_some_and(|connections| {
+            connections.iter().fold(false, |delivered, (_, tx)| tx.send(msg.clone()).is_ok() || delivered)
+        })
+    }
+    /// Sends the message to every connection, returns how many received it
+    pub fn broadcast(&self, msg: SessionMessage) -> usize {
+        self.connections.iter().map(|connections| {
+            connections.iter().filter(|(_, tx)| tx.send(msg.clone()).is_ok()).count()
+        }).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn multiple_connections() {
+        let sessions = Sessions::default();
+        let uuid = Uuid::from_u128(1);
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let first = sessions.insert(uuid, tx1);
+        sessions.insert(uuid, tx2);
+
+        assert!(sessions.send(&uuid, SessionMessage::Banned));
+        assert!(matches!(rx1.recv().await, Some(SessionMessage::Banned)));
+        assert!(matches!(rx2.recv().await, Some(SessionMessage::Banned)));
+        assert_eq!(sessions.broadcast(SessionMessage::Kicked), 2);
+        assert!(matches!(rx1.recv().await, Some(SessionMessage::Kicked)));
+
+        sessions.remove(&uuid, first);
+        // Removed connections are closed, even if they have weak senders around
+        assert!(rx1.recv().await.is_none());
+        let replaced = sessions.take(&uuid);
+        assert_eq!(replaced.len(), 1);
+        assert!(!sessions.contains(&uuid));
+        assert!(!sessions.send(&uuid, SessionMessage::Banned));
+
+        // Queued messages are still delivered after the connection is taken
+        replaced[0].send(SessionMessage::Replaced).unwrap();
+        drop(replaced);
+        assert!(matches!(rx2.recv().await, Some(SessionMessage::Kicked)));
+        assert!(matches!(rx2.recv().await, Some(SessionMessage::Replaced)));
+        assert!(rx2.recv().await.is_none());
+    }
+
+    fn session(uuid: Uuid) -> WSSession {
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let (pings_tx, pings_rx) = mpsc::channel(1);
+        let limits = crate::state::UserLimits { max_avatar_size: 0, max_uncompressed_size: 0, max_avatars: 0, ping_size: 1024, ping_rate: 32, max_ping_violations: None };
+        WSSession {
+            id: 1,
+            user: crate::auth::Userinfo { uuid, ..Default::default() },
+            control: control.downgrade(),
+            control_rx,
+            pings_tx,
+            pings_rx,
+            subscriptions: HashSet::new(),
+            token: String::new(),
+            expires: std::time::Instant::now(),
+            ping_limiter: super::super::PingLimiter::new(&limits),
+            lagged: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn subscriptions() {
+        let subscriptions = super::super::Subscriptions::default();
+        let mut session = session(Uuid::from_u128(1));
+        let (first, second, third) = (Uuid::from_u128(2), Uuid::from_u128(3), Uuid::from_u128(4));
+
+        assert_eq!(session.subscribe(&subscriptions, session.user.uuid, 2), SubscribeResult::Own);
+        assert_eq!(session.subscribe(&subscriptions, first, 2), SubscribeResult::Subscribed);
+        // Duplicates neither count against the limit nor queue pings twice
+        assert_eq!(session.subscribe(&subscriptions, first, 2), SubscribeResult::AlreadySubscribed);
+        assert_eq!(subscriptions.count(&first), 1);
+        assert_eq!(session.subscribe(&subscriptions, second, 2), SubscribeResult::Subscribed);
+        assert_eq!(session.subscribe(&subscriptions, third, 2), SubscribeResult::LimitReached);
+        assert_eq!(subscriptions.count(&third), 0);
+
+        // Unsubscribing from a stranger changes nothing
+        assert!(!session.unsubscribe(&subscriptions, &third));
+        assert_eq!(session.subscriptions.len(), 2);
+        // Freed slots can be reused
+        assert!(session.unsubscribe(&subscriptions, &first));
+        assert_eq!(subscriptions.count(&first), 0);
+        assert_eq!(session.subscribe(&subscriptions, third, 2), SubscribeResult::Subscribed);
+
+        session.unsubscribe_all(&subscriptions);
+        assert!(session.subscriptions.is_empty());
+        assert_eq!(subscriptions.count(&second) + subscriptions.count(&third), 0);
+    }
+}
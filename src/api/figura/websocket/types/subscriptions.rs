@@ -0,0 +1,165 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use axum::body::Bytes;
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use uuid::Uuid;
+
+use crate::PINGS_LAGGED;
+
+use super::SessionMessage;
+
+/// Connection receiving pings of another player
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    /// Id from [`super::Sessions::insert`]
+    pub session: u64,
+    /// Queue of pings, shared by all subscriptions of the connection
+    pub pings: mpsc::Sender<Bytes>,
+    /// For messages that can't be skipped. Weak, so a subscription doesn't keep a removed connection open
+    pub control: mpsc::WeakUnboundedSender<SessionMessage>,
+    /// Pings skipped because the connection couldn't keep up
+    pub lagged: Arc<AtomicU64>,
+}
+
+/// Who receives pings of whom. Pings are put right into the queues of subscribed connections
+/// and all of them share the same buffer, so fan-out costs neither copies nor tasks.
+///
+/// A ping is dropped for a connection whose queue is full: pings are frequent and a fresh one
+/// soon replaces a skipped one, while waiting for a slow connection would hold up everyone else.
+/// Events go through [`Subscriptions::notify`] and are never dropped. A connection sends them before its queued pings,
+/// so a ping published after an event never overtakes it, while older pings may arrive after it.
+#[derive(Debug, Default)]
+pub struct Subscriptions(DashMap<Uuid, Vec<Subscriber>>);
+
+impl Subscriptions {
+    /// Returns false if the connection is already subscribed
+    pub fn subscribe(&self, publisher: Uuid, subscriber: Subscriber) -> bool {
+        let mut subscribers = self.0.entry(publisher).or_default();
+        if subscribers.iter().any(|other| other.session == subscriber.session) {
+            return false;
+        }
+        subscribers.push(subscriber);
+        true
+    }
+
+    /// Returns false if the connection wasn't subscribed
+    pub fn unsubscribe(&self, publisher: &Uuid, session: u64) -> bool {
+        let mut removed = false;
+        self.0.remove_if_mut(publisher, |_, subscribers| {
+            let len = subscribers.len();
+            subscribers.retain(|subscriber| subscriber.session != session);
+            removed = subscribers.len() != len;
+            subscribers.is_empty()
+        });
+        removed
+    }
+
+    /// Number of connections subscribed to the player
+    pub fn count(&self, publisher: &Uuid) -> usize {
+        self.0.get(publisher).map_or(0, |subscribers| subscribers.len())
+    }
+
+    /// Queues the ping for every subscriber of the player without waiting, returns how many got it.
+    /// Subscribers with a full queue skip the ping, closed ones are forgotten.
+    pub fn publish(&self, publisher: &Uuid, msg: Bytes) -> usize {
+        let mut delivered = 0;
+        let mut closed = false;
+        if let Some(subscribers) = self.0.get(publisher) {
+            for subscriber in subscribers.iter() {
+                match subscriber.pings.try_send(msg.clone()) {
+                    Ok(()) => delivered += 1,
+                    Err(TrySendError::Full(_)) => {
+                        PINGS_LAGGED.inc();
+                        subscriber.lagged.fetch_add(1, Ordering::Relaxed);
+                    },
+                    Err(TrySendError::Closed(_)) => closed = true,
+                }
+            }
+        }
+        // Normally connections unsubscribe when closing, this is for the ones that didn't manage to
+        if closed {
+            self.0.remove_if_mut(publisher, |_, subscribers| {
+                subscribers.retain(|subscriber| !subscriber.pings.is_closed());
+                subscribers.is_empty()
+            });
+        }
+        delivered
+    }
+
+    /// Sends the message to every subscriber of the player, nobody skips it. Returns how many got it.
+    pub fn notify(&self, publisher: &Uuid, msg: Bytes) -> usize {
+        self.0.get(publisher).map_or(0, |subscribers| {
+            subscribers.iter()
+                .filter(|subscriber| subscriber.control.upgrade().is_some_and(|tx| tx.send(SessionMessage::Ping(msg.clone())).is_ok()))
+                .count()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriber(session: u64, capacity: usize) -> (Subscriber, mpsc::Receiver<Bytes>) {
+        let (pings, rx) = mpsc::channel(capacity);
+        // Nobody holds the control sender, see `events_are_not_dropped` for it
+        let control = mpsc::unbounded_channel().0.downgrade();
+        (Subscriber { session, pings, control, lagged: Arc::default() }, rx)
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let subscriptions = Subscriptions::default();
+        let publisher = Uuid::from_u128(1);
+        let (first, mut first_rx) = subscriber(1, 1);
+        let (second, mut second_rx) = subscriber(2, 8);
+        let lagged = Arc::clone(&first.lagged);
+
+        assert!(subscriptions.subscribe(publisher, first.clone()));
+        assert!(!subscriptions.subscribe(publisher, first));
+        assert!(subscriptions.subscribe(publisher, second));
+        assert_eq!(subscriptions.count(&publisher), 2);
+
+        let ping = Bytes::from_static(b"ping");
+        assert_eq!(subscriptions.publish(&publisher, ping.clone()), 2);
+        // The first queue is full, the ping is skipped only for it
+        assert_eq!(subscriptions.publish(&publisher, ping.clone()), 1);
+        assert_eq!(lagged.load(Ordering::Relaxed), 1);
+        assert!(first_rx.recv().await.is_some_and(|msg| msg.as_ptr() == ping.as_ptr()));
+        assert!(second_rx.recv().await.is_some_and(|msg| msg.as_ptr() == ping.as_ptr()));
+
+        assert!(subscriptions.unsubscribe(&publisher, 1));
+        assert!(!subscriptions.unsubscribe(&publisher, 1));
+        // Closed connections are dropped on the next publish
+        drop(second_rx);
+        assert_eq!(subscriptions.publish(&publisher, ping), 0);
+        assert_eq!(subscriptions.count(&publisher), 0);
+    }
+
+    #[tokio::test]
+    async fn events_are_not_dropped() {
+        let subscriptions = Subscriptions::default();
+        let publisher = Uuid::from_u128(1);
+        let (control, mut control_rx) = mpsc::unbounded_channel();
+        let (mut subscriber, _pings_rx) = subscriber(1, 1);
+        subscriber.control = control.downgrade();
+        subscriptions.subscribe(publisher, subscriber);
+
+        let ping = Bytes::from_static(b"ping");
+        assert_eq!(subscriptions.publish(&publisher, ping.clone()), 1);
+        assert_eq!(subscriptions.publish(&publisher, ping), 0);
+        // The ping queue is full, events get through anyway
+        let event = Bytes::from_static(b"event");
+        for _ in 0..100 {
+            assert_eq!(subscriptions.notify(&publisher, event.clone()), 1);
+        }
+        for _ in 0..100 {
+            assert!(matches!(control_rx.recv().await, Some(SessionMessage::Ping(msg)) if msg == event));
+        }
+        // The subscription doesn't keep the connection open
+        drop(control);
+        assert!(control_rx.recv().await.is_none());
+        assert_eq!(subscriptions.notify(&publisher, event), 0);
+    }
+}
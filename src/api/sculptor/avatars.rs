@@ -1,28 +1,34 @@
-use axum::{body::Bytes, extract::{Path, State}};
-use tokio::{fs, io::{self, BufWriter}};
+use std::collections::HashMap;
+
+use axum::{body::Body, extract::{Path, Query, State}, http::HeaderMap, Json};
 use tracing::warn;
 use uuid::Uuid;
 
-use crate::{api::figura::profile::send_event, auth::Token, ApiResult, AppState, AVATARS_VAR};
+use crate::{api::{errors::{error_and_log, internal_and_log}, figura::profile::{avatar_id, read_avatar, send_event, validate_avatar}}, auth::{Token, Userinfo}, moon::{self, AvatarMeta}, state::AdminRole, storage::AvatarKey, ApiResult, AppState};
 
 pub async fn upload_avatar(
     Path(uuid): Path<Uuid>,
+    Query(query): Query<HashMap<String, String>>,
     Token(token): Token,
     State(state): State<AppState>,
-    body: Bytes,
+    headers: HeaderMap,
+    body: Body,
 ) -> ApiResult<&'static str> {
-    let request_data = body;
-
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Admin)?;
 
+    let key = AvatarKey::new(uuid, avatar_id(query.get("id").cloned())?);
     tracing::info!(
-        "trying to upload the avatar for {}",
-        uuid,
+        "{} trying to upload the avatar for {}",
+        admin,
+        key,
     );
 
-    let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, &uuid);
-    let mut file = BufWriter::new(fs::File::create(&avatar_file).await.unwrap());
-    io::copy(&mut request_data.as_ref(), &mut file).await.unwrap();
+    // The owner's quota still applies, an avatar that doesn't fit is useless for them anyway
+    let rank = state.user_manager.get_by_uuid(&uuid).map(|user| user.rank.clone()).unwrap_or_else(|| Userinfo::default().rank);
+    let limits = state.config.read().await.limits_for(&uuid, &rank);
+    let request_data = read_avatar(&headers, body, &limits).await?;
+    validate_avatar(&request_data, &limits).await?;
+    state.avatars.put(&key, request_data, None).await.map_err(internal_and_log)?;
     send_event(&state, &uuid).await;
 
     Ok("ok")
@@ -30,25 +36,51 @@ pub async fn upload_avatar(
 
 pub async fn delete_avatar(
     Path(uuid): Path<Uuid>,
+    Query(query): Query<HashMap<String, String>>,
     Token(token): Token,
     State(state): State<AppState>
 ) -> ApiResult<&'static str> {
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
 
+    let key = AvatarKey::new(uuid, avatar_id(query.get("id").cloned())?);
     tracing::info!(
-        "trying to delete the avatar for {}",
-        uuid,
+        "{} trying to delete the avatar for {}",
+        admin,
+        key,
     );
 
-    let avatar_file = format!("{}/{}.moon", *AVATARS_VAR, &uuid);
-    match fs::remove_file(avatar_file).await {
-        Ok(_) => {},
-        Err(_) => {
-            warn!("avatar doesn't exist");
-            return Err(crate::ApiError::NotFound)
-        }
-    };
+    if !state.avatars.delete(&key).await.map_err(internal_and_log)? {
+        warn!("avatar doesn't exist");
+        return Err(crate::ApiError::NotFound)
+    }
     send_event(&state, &uuid).await;
 
     Ok("ok")
+}
+
+pub async fn avatar_meta(
+    Path(uuid): Path<Uuid>,
+    Query(query): Query<HashMap<String, String>>,
+    Token(token): Token,
+    State(state): State<AppState>
+) -> ApiResult<Json<AvatarMeta>> {
+    let admin = state.config.read().await.authorize(&token, AdminRole::ReadOnly)?;
+
+    let key = AvatarKey::new(uuid, avatar_id(query.get("id").cloned())?);
+    tracing::info!("{admin} inspects avatar {key}");
+    let data = state.avatars.get(&key).await.map_err(internal_and_log)?.ok_or(crate::ApiError::NotFound)?;
+    let rank = state.user_manager.get_by_uuid(&uuid).map(|user| user.rank.clone()).unwrap_or_else(|| Userinfo::default().rank);
+    // Stored avatars may predate a lowered quota, so they are allowed to grow relative to their own size too
+    let max_uncompressed = {
+        let config = state.config.read().await;
+        let own = moon::uncompressed_limit(data.len() as u64, config.limitations.uncompressed_ratio);
+        config.limits_for(&uuid, &rank).max_uncompressed_size.max(own)
+    };
+    let meta = tokio::task::spawn_blocking(move || moon::decode(&data, max_uncompressed).map(|moon| moon.meta()))
+        .await
+        .map_err(internal_and_log)?
+        // Avatars uploaded before validation was introduced can still be broken
+        .map_err(|e| error_and_log(&e, crate::ApiError::UnprocessableEntity(format!("stored avatar is broken: {e}"))))?;
+
+    Ok(Json(meta))
 }
\ No newline at end of file
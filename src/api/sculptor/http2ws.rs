@@ -4,7 +4,7 @@ use axum::extract::{Query, State};
 use tracing::instrument;
 use uuid::Uuid;
 
-use crate::{api::errors::{error_and_log, internal_and_log}, auth::Token, ApiResult, AppState};
+use crate::{api::errors::error_and_log, auth::Token, state::AdminRole, ApiResult, AppState};
 
 /*
     FIXME: need to refactor
@@ -14,8 +14,8 @@ pub(super) async fn verify(
     Token(token): Token,
     State(state): State<AppState>,
 ) -> ApiResult<&'static str> {
-    state.config.read().await.clone()
-        .verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::ReadOnly)?;
+    tracing::info!("{admin} verified the token");
     Ok("ok")
 }
 
@@ -27,7 +27,8 @@ pub(super) async fn raw(
     body: String,
 ) -> ApiResult<&'static str> {
     tracing::trace!(body = body);
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Admin)?;
+    tracing::info!("{admin} sends raw message");
     let mut payload = vec![0; body.len() / 2];
     faster_hex::hex_decode(body.as_bytes(), &mut payload).map_err(|err| { tracing::warn!("not raw data"); error_and_log(err, crate::ApiError::NotAcceptable) })?;
 
@@ -39,16 +40,16 @@ pub(super) async fn raw(
     if let Some(uuid) = query.get("uuid") {
         // for one
         let uuid = Uuid::parse_str(uuid).map_err(|err| { tracing::warn!("invalid uuid"); error_and_log(err, crate::ApiError::BadRequest) })?;
-        let tx = state.session.get(&uuid).ok_or_else(|| { tracing::warn!("unknown uuid"); crate::ApiError::NotFound })?;
-        tx.value().send(crate::api::figura::SessionMessage::Ping(payload)).await.map_err(internal_and_log)?;
+        if !state.session.send(&uuid, crate::api::figura::SessionMessage::Ping(payload.into())) {
+            tracing::warn!("unknown uuid");
+            return Err(crate::ApiError::NotFound)
+        }
         Ok("ok")
     } else if query.contains_key("all") {
         // for all
-        for tx in state.session.iter() {
-            if let Err(e) = tx.value().send(crate::api::figura::SessionMessage::Ping(payload.clone())).await {
-                tracing::debug!(error = ?e , "error while sending to session");
-            }
-        };
+        let payload = axum::body::Bytes::from(payload);
+        let delivered = state.session.broadcast(crate::api::figura::SessionMessage::Ping(payload));
+        tracing::debug!("sent to {delivered} sessions");
         Ok("ok")
     } else {
         tracing::error!("unreachable code!");
@@ -64,14 +65,17 @@ pub(super) async fn sub_raw(
     body: String,
 ) -> ApiResult<&'static str> {
     tracing::trace!(body = body);
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Admin)?;
+    tracing::info!("{admin} sends raw message");
     let mut payload = vec![0; body.len() / 2];
     faster_hex::hex_decode(body.as_bytes(), &mut payload).map_err(|err| { tracing::warn!("not raw data"); error_and_log(err, crate::ApiError::NotAcceptable) })?;
 
     if let Some(uuid) = query.get("uuid") {
         let uuid = Uuid::parse_str(uuid).map_err(|err| { tracing::warn!("invalid uuid"); error_and_log(err, crate::ApiError::BadRequest) })?;
-        let tx = state.subscribes.get(&uuid).ok_or_else(|| { tracing::warn!("unknown uuid"); crate::ApiError::NotFound })?;
-        tx.value().send(payload).map_err(internal_and_log)?;
+        if state.subscriptions.publish(&uuid, payload.into()) == 0 {
+            tracing::warn!("nobody is subscribed to uuid");
+            return Err(crate::ApiError::NotFound)
+        }
         Ok("ok")
     } else {
         tracing::warn!("uuid doesnt defined");
@@ -0,0 +1,48 @@
+use axum::{extract::State, Json};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::{api::errors::internal_and_log, auth::{IpBanList, IpRange, Token}, state::AdminRole, ApiResult, AppState};
+
+#[derive(Deserialize)]
+pub(super) struct IpBanRequest {
+    ip: IpRange,
+}
+
+pub(super) async fn list(
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> ApiResult<Json<IpBanList>> {
+    let config = state.config.read().await.clone();
+    let admin = config.authorize(&token, AdminRole::ReadOnly)?;
+
+    info!("{admin} lists IP bans");
+
+    Ok(Json(state.ip_bans.list(&config.network.banned_ips)))
+}
+
+pub(super) async fn ban(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(json): Json<IpBanRequest>
+) -> ApiResult<&'static str> {
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
+
+    info!("{admin} bans IP range: {}", json.ip);
+
+    state.ip_bans.ban(json.ip).await.map_err(internal_and_log)?;
+    Ok("ok")
+}
+
+pub(super) async fn unban(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Json(json): Json<IpBanRequest>
+) -> ApiResult<&'static str> {
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
+
+    info!("{admin} unbans IP range: {}", json.ip);
+
+    state.ip_bans.unban(&json.ip).await.map_err(internal_and_log)?;
+    Ok("ok")
+}
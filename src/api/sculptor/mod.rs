@@ -1,20 +1,28 @@
-use axum::{extract::DefaultBodyLimit, routing::{delete, get, post, put}, Router};
+use axum::{routing::{delete, get, post, put}, Router};
 use crate::AppState;
 
 mod http2ws;
 mod users;
 mod avatars;
+mod providers;
+mod ips;
 
-pub fn router(limit: usize) -> Router<AppState> {
+pub fn router() -> Router<AppState> {
     Router::new()
         .route("/verify", get(http2ws::verify))
         .route("/raw", post(http2ws::raw))
         .route("/sub/raw", post(http2ws::sub_raw))
+        .route("/auth/providers", get(providers::list))
+        .route("/ip/list", get(ips::list))
+        .route("/ip/ban", post(ips::ban))
+        .route("/ip/unban", post(ips::unban))
         .route("/user/list", get(users::list))
         .route("/user/sessions", get(users::list_sessions))
         .route("/user/create", post(users::create_user))
         .route("/user/{uuid}/ban", post(users::ban))
         .route("/user/{uuid}/unban", post(users::unban))
-        .route("/avatar/{uuid}", put(avatars::upload_avatar).layer(DefaultBodyLimit::max(limit)))
+        .route("/user/{uuid}/kick", post(users::kick))
+        .route("/avatar/{uuid}", put(avatars::upload_avatar))
         .route("/avatar/{uuid}", delete(avatars::delete_avatar))
+        .route("/avatar/{uuid}/meta", get(avatars::avatar_meta))
 }
\ No newline at end of file
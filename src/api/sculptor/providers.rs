@@ -0,0 +1,16 @@
+use axum::{extract::State, Json};
+use tracing::info;
+
+use crate::{auth::{ProviderStatus, Token}, state::AdminRole, ApiResult, AppState};
+
+pub(super) async fn list(
+    Token(token): Token,
+    State(state): State<AppState>,
+) -> ApiResult<Json<Vec<ProviderStatus>>> {
+    let config = state.config.read().await.clone();
+    let admin = config.authorize(&token, AdminRole::ReadOnly)?;
+
+    info!("{admin} lists auth providers");
+
+    Ok(Json(state.providers_health.status(&config.auth_providers.0)))
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use axum::{
     extract::{Path, State},
     Json
@@ -6,16 +8,16 @@ use dashmap::DashMap;
 use tracing::{debug, info};
 use uuid::Uuid;
 
-use crate::{auth::{Token, Userinfo}, ApiResult, AppState};
+use crate::{auth::{Token, Userinfo}, state::AdminRole, ApiResult, AppState};
 
 pub(super) async fn create_user(
     Token(token): Token,
     State(state): State<AppState>,
     Json(json): Json<Userinfo>
 ) -> ApiResult<&'static str> {
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Admin)?;
 
-    debug!("Creating new user: {json:?}");
+    debug!("{admin} creates new user: {json:?}");
     
     state.user_manager.insert_user(json.uuid, json);
     Ok("ok")
@@ -26,11 +28,11 @@ pub(super) async fn ban(
     State(state): State<AppState>,
     Path(uuid): Path<Uuid>
 ) -> ApiResult<&'static str> {
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
 
-    info!("Trying ban user: {uuid}");
+    info!("{admin} trying ban user: {uuid}");
     
-    if let Some(tx) = state.session.get(&uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+    state.session.send(&uuid, crate::api::figura::SessionMessage::Banned);
     state.user_manager.ban(&Userinfo { uuid, banned: true, ..Default::default() });
     Ok("ok")
 }
@@ -40,28 +42,50 @@ pub(super) async fn unban(
     State(state): State<AppState>,
     Path(uuid): Path<Uuid>
 ) -> ApiResult<&'static str> {
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
 
-    info!("Trying unban user: {uuid}");
+    info!("{admin} trying unban user: {uuid}");
     
     state.user_manager.unban(&uuid);
     Ok("ok")
 }
 
+pub(super) async fn kick(
+    Token(token): Token,
+    State(state): State<AppState>,
+    Path(uuid): Path<Uuid>
+) -> ApiResult<&'static str> {
+    let admin = state.config.read().await.authorize(&token, AdminRole::Moderator)?;
+
+    info!("{admin} trying kick user: {uuid}");
+
+    // Without a token the client has to authenticate again
+    state.user_manager.remove(&uuid);
+    if !state.session.send(&uuid, crate::api::figura::SessionMessage::Kicked) {
+        return Err(crate::ApiError::NotFound);
+    }
+    Ok("ok")
+}
+
 pub(super) async fn list(
     Token(token): Token,
     State(state): State<AppState>,
 ) -> ApiResult<Json<DashMap<Uuid, Userinfo>>> {
-    state.config.read().await.clone().verify_token(&token)?;
+    let admin = state.config.read().await.authorize(&token, AdminRole::ReadOnly)?;
+
+    info!("{admin} lists users");
 
     Ok(Json(state.user_manager.get_all_registered()))
 }
 
+/// Number of session tokens of every player. Tokens themselves are never shown, they would let the reader impersonate players
 pub(super) async fn list_sessions(
     Token(token): Token,
     State(state): State<AppState>,
-) -> ApiResult<Json<DashMap<String, Uuid>>> {
-    state.config.read().await.clone().verify_token(&token)?;
+) -> ApiResult<Json<HashMap<Uuid, usize>>> {
+    let admin = state.config.read().await.authorize(&token, AdminRole::ReadOnly)?;
+
+    info!("{admin} lists sessions");
 
-    Ok(Json(state.user_manager.get_all_authenticated()))
+    Ok(Json(state.user_manager.count_sessions_by_user()))
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::{Duration, Instant}};
 
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
 use axum::{
     extract::{FromRequestParts, OptionalFromRequestParts, State}, http::{request::Parts, StatusCode}
 };
@@ -9,9 +9,9 @@ use thiserror::Error;
 use tracing::{debug, error, instrument, trace, warn};
 use uuid::Uuid;
 
-use crate::{ApiError, ApiResult, AppState, TIMEOUT, USER_AGENT};
+use crate::{state::{AuthConfig, Config}, ApiError, ApiResult, AppState, AUTH_PENDING, AUTH_PENDING_EXPIRED, TIMEOUT, USER_AGENT};
 
-use super::types::*;
+use super::{types::*, CircuitBreakerConfig, Outcome, ProvidersHealth, UserStore, UserWriter};
 
 // It's an extractor that pulls a token from the Header.
 #[derive(PartialEq, Debug)]
@@ -68,11 +68,21 @@ where
 // End Extractor
 
 // Work with external APIs
-/// Get UUID from JSON response
-fn get_id_json(json: &serde_json::Value) -> Result<Uuid, uuid::Error> {
-    trace!("json: {json:#?}"); // For debugging, we'll get to this later!
-    let uuid = Uuid::parse_str(json.get("id").unwrap().as_str().unwrap())?;
-    Ok(uuid)
+/// Get profile from JSON response
+fn parse_profile(provider: &AuthProvider, body: &str) -> Result<JoinedProfile, FetchError> {
+    let json: serde_json::Value = serde_json::from_str(body)?;
+    trace!("json: {json:#?}");
+    let id = json.pointer(&provider.uuid_pointer)
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| FetchError::MissingField(provider.uuid_pointer.clone()))?;
+    let name = json.pointer(&provider.name_pointer).and_then(serde_json::Value::as_str).map(str::to_string);
+    // Properties are nice to have, broken ones don't make the player less authenticated
+    let properties = json.pointer(&provider.properties_pointer)
+        .and_then(|properties| serde_json::from_value(properties.clone())
+            .inspect_err(|e| debug!("{} returned invalid properties: {e}", provider.name))
+            .ok())
+        .unwrap_or_default();
+    Ok(JoinedProfile { uuid: Uuid::parse_str(id)?, name, properties, provider: provider.clone() })
 }
 
 #[derive(Debug, Error)]
@@ -81,70 +91,164 @@ enum FetchError {
     WrongResponse(u16, Result<String, reqwest::Error>),
     #[error(transparent)]
     SendError(#[from] reqwest::Error),
-    #[error(transparent)]
-    Other(#[from] anyhow::Error),
+    #[error("username is not allowed by the offline provider")]
+    NotAllowed,
+    #[error("provider may not confirm {0}")]
+    ForeignUuid(Uuid),
+    #[error("provider is skipped after repeated failures")]
+    CircuitOpen,
+    #[error("response is not a valid JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("response has no string at {0}")]
+    MissingField(String),
+    #[error("response has invalid UUID: {0}")]
+    InvalidUuid(#[from] uuid::Error),
+}
 
+/// UUID of the player on an offline-mode server, same as Java's `UUID.nameUUIDFromBytes("OfflinePlayer:<name>")`
+pub fn offline_uuid(username: &str) -> Uuid {
+    uuid::Builder::from_md5_bytes(md5::compute(format!("OfflinePlayer:{username}")).0).into_uuid()
 }
 
 async fn fetch_json(
     auth_provider: &AuthProvider,
     server_id: &str,
     username: &str,
-) -> Result<(Uuid, AuthProvider), FetchError> {
-    let client = reqwest::Client::builder().timeout(TIMEOUT).user_agent(USER_AGENT).build().unwrap();
-    let url = auth_provider.url.clone();
-
-    let res = client
-        .get(url)
-        .query(&[("serverId", server_id), ("username", username)])
-        .send()
-        .await?;
+) -> Result<JoinedProfile, FetchError> {
+    if auth_provider.kind == AuthProviderKind::Offline {
+        // The UUID is derived from the name, so "steve" and "Steve" would be different players.
+        // Listed players always get the name as it is spelled in the allowlist
+        let username = match auth_provider.allowlist.iter().find(|allowed| allowed.eq_ignore_ascii_case(username)) {
+            Some(allowed) => allowed.as_str(),
+            None if auth_provider.allowlist.is_empty() => username,
+            None => return Err(FetchError::NotAllowed),
+        };
+        warn!("{username} authenticated by offline provider {}, identity is NOT verified!", auth_provider.name);
+        return Ok(JoinedProfile {
+            uuid: offline_uuid(username),
+            name: Some(username.to_string()),
+            properties: Vec::new(),
+            provider: auth_provider.clone(),
+        });
+    }
+
+    let timeout = auth_provider.timeout.map_or(TIMEOUT, Duration::from_secs);
+    let client = reqwest::Client::builder().timeout(timeout).user_agent(USER_AGENT).build()?;
+    let params = [(auth_provider.server_id_param.as_str(), server_id), (auth_provider.username_param.as_str(), username)];
+
+    let mut req = match auth_provider.method {
+        RequestMethod::Get => client.get(&auth_provider.url).query(&params),
+        RequestMethod::Post => client.post(&auth_provider.url).json(&HashMap::from(params)),
+    };
+    for (name, value) in &auth_provider.headers {
+        req = req.header(name, value);
+    }
+    let res = req.send().await?;
     trace!("{res:?}");
     match res.status().as_u16() {
         200 => {
-            let json = serde_json::from_str::<serde_json::Value>(&res.text().await?).with_context(|| "Cant deserialize".to_string())?;
-            let uuid = get_id_json(&json).with_context(|| "Cant get UUID".to_string())?;
-            Ok((uuid, auth_provider.clone()))
+            parse_profile(auth_provider, &res.text().await?)
         }
         _ => Err(FetchError::WrongResponse(res.status().as_u16(), res.text().await)),
     }
 }
 
+/// Asks the provider unless its circuit is open and records how it went
+async fn fetch_tracked(
+    provider: &AuthProvider,
+    health: &ProvidersHealth,
+    breaker: &CircuitBreakerConfig,
+    server_id: &str,
+    username: &str,
+) -> Result<JoinedProfile, FetchError> {
+    if !health.try_acquire(&provider.name, breaker) {
+        return Err(FetchError::CircuitOpen);
+    }
+    let start = Instant::now();
+    // A provider must not claim players from the namespace of another one
+    let res = fetch_json(provider, server_id, username).await.and_then(|profile| {
+        if provider.owns(&profile.uuid) {
+            Ok(profile)
+        } else {
+            warn!("{} confirmed {username} as {}, which is outside of its UUID namespace, ignored", provider.name, profile.uuid);
+            Err(FetchError::ForeignUuid(profile.uuid))
+        }
+    });
+    let outcome = match &res {
+        Ok(_) => Outcome::Success,
+        // Session servers answer 204 for unknown players, but 5xx means they are broken
+        Err(FetchError::WrongResponse(code, _)) if *code < 500 => Outcome::Miss,
+        Err(FetchError::NotAllowed | FetchError::ForeignUuid(_)) => Outcome::Miss,
+        Err(_) => Outcome::Failure,
+    };
+    let error = res.as_ref().err().filter(|_| outcome == Outcome::Failure).map(ToString::to_string);
+    health.record(&provider.name, outcome, start.elapsed(), error, breaker);
+    res
+}
+
 pub async fn has_joined(
     AuthProviders(authproviders): AuthProviders,
+    config: &AuthConfig,
+    health: Arc<ProvidersHealth>,
     server_id: &str,
     username: &str,
-) -> anyhow::Result<Option<(Uuid, AuthProvider)>> {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-
-    for provider in &authproviders {
-        tokio::spawn(fetch_and_send(
-            provider.clone(),
-            server_id.to_string(),
-            username.to_string(),
-            tx.clone()
-        ));
-    } 
+) -> anyhow::Result<Option<JoinedProfile>> {
+    // Providers are asked only about usernames from their namespace
+    let authproviders: Vec<AuthProvider> = authproviders.into_iter().filter(|provider| provider.accepts(username)).collect();
+    if authproviders.is_empty() {
+        debug!("No auth providers are responsible for {username}");
+    }
+
     let mut errors = Vec::new(); // Counting fetches what returns errors
     let mut misses = Vec::new(); // Counting non OK results
-    let mut prov_count: usize = authproviders.len();
-    while prov_count > 0 {
-        if let Some(fetch_res) = rx.recv().await {
-            match fetch_res {
-                Ok(data) => return Ok(Some(data)),
-                Err(err) => {
-                    match err {
-                        FetchError::WrongResponse(code, data) => misses.push((code, data)),
-                        FetchError::SendError(err) => errors.push(err.to_string()),
-                        FetchError::Other(err) => errors.push(err.to_string()),
+    let mut count = |err: FetchError| {
+        match err {
+            FetchError::WrongResponse(code, data) => misses.push((code, data)),
+            FetchError::NotAllowed | FetchError::ForeignUuid(_) => misses.push((403, Ok(err.to_string()))),
+            // Skipped on purpose, the failures that opened the circuit were reported back then
+            FetchError::CircuitOpen => misses.push((503, Ok(err.to_string()))),
+            FetchError::SendError(_) | FetchError::InvalidJson(_) | FetchError::MissingField(_) | FetchError::InvalidUuid(_) => {
+                errors.push(err.to_string())
+            },
+        }
+    };
+
+    match config.strategy {
+        AuthStrategy::Ordered => {
+            for provider in &authproviders {
+                match fetch_tracked(provider, &health, &config.circuit_breaker, server_id, username).await {
+                    Ok(data) => return Ok(Some(data)),
+                    Err(err) => count(err),
+                }
+            }
+        },
+        AuthStrategy::Race => {
+            let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+            for provider in &authproviders {
+                tokio::spawn(fetch_and_send(
+                    provider.clone(),
+                    Arc::clone(&health),
+                    config.circuit_breaker.clone(),
+                    server_id.to_string(),
+                    username.to_string(),
+                    tx.clone()
+                ));
+            }
+            let mut prov_count: usize = authproviders.len();
+            while prov_count > 0 {
+                if let Some(fetch_res) = rx.recv().await {
+                    match fetch_res {
+                        Ok(data) => return Ok(Some(data)),
+                        Err(err) => count(err),
                     }
-                },
+                } else {
+                    error!("Unexpected behavior!");
+                    return Err(anyhow!("Something went wrong..."))
+                }
+                prov_count -= 1;
             }
-        } else {
-            error!("Unexpected behavior!");
-            return Err(anyhow!("Something went wrong..."))
-        }
-        prov_count -= 1;
+        },
     }
 
     // Choosing what error return
@@ -163,23 +267,35 @@ pub async fn has_joined(
 
 async fn fetch_and_send(
     provider: AuthProvider,
+    health: Arc<ProvidersHealth>,
+    breaker: CircuitBreakerConfig,
     server_id: String,
     username: String,
-    tx: tokio::sync::mpsc::Sender<Result<(Uuid, AuthProvider), FetchError>>
+    tx: tokio::sync::mpsc::Sender<Result<JoinedProfile, FetchError>>
 ) {
-    let _ = tx.send(fetch_json(&provider, &server_id, &username).await)
+    let _ = tx.send(fetch_tracked(&provider, &health, &breaker, &server_id, &username).await)
         .await.map_err( |err| trace!("fetch_and_send error [note: ok res returned and mpsc clossed]: {err:?}"));
 }
 
 // User manager
+#[derive(Debug, Clone, Copy)]
+struct AuthSession {
+    uuid: Uuid,
+    expires: Instant,
+}
+
 #[derive(Debug, Clone)]
 pub struct UManager {
     /// Users with incomplete authentication
-    pending: Arc<DashMap<String, String>>, // <SHA1 serverId, USERNAME> TODO: Add automatic purge
+    pending: Arc<DashMap<String, (String, Instant)>>, // <SHA1 serverId, (USERNAME, issued at)>
     /// Authenticated users TODO: Change name to sessions
-    authenticated: Arc<DashMap<String, Uuid>>, // <SHA1 serverId, Userinfo>
+    authenticated: Arc<DashMap<String, AuthSession>>, // <Session token, AuthSession>
+    /// Session tokens of every user, so they can be revoked without scanning all sessions
+    tokens: Arc<DashMap<Uuid, HashSet<String>>>,
     /// Registered users
     registered: Arc<DashMap<Uuid, Userinfo>>,
+    /// Durable copy of registered users
+    store: Option<UserWriter>,
 }
 
 impl Default for UManager {
@@ -194,56 +310,106 @@ impl UManager {
             pending: Arc::new(DashMap::new()),
             registered: Arc::new(DashMap::new()),
             authenticated: Arc::new(DashMap::new()),
+            tokens: Arc::new(DashMap::new()),
+            store: None,
+        }
+    }
+    /// Creates a manager backed by a persistent store and fills it with previously registered users.
+    pub fn with_store(store: UserStore) -> anyhow::Result<Self> {
+        let users = store.load()?;
+        let manager = Self { store: Some(UserWriter::new(store)), ..Self::new() };
+        for user in users {
+            manager.registered.insert(user.uuid, user);
+        }
+        Ok(manager)
+    }
+    /// Queues the user for the store (if any). Must be called under the entry guard of the change,
+    /// so changes of the same user reach the store in the order they were made.
+    fn persist(&self, user: &Userinfo) {
+        if let Some(store) = &self.store {
+            store.save(user.clone());
         }
     }
     pub fn get_all_registered(&self) -> DashMap<Uuid, Userinfo> {
         self.registered.as_ref().clone()
     }
-    pub fn get_all_authenticated(&self) -> DashMap<String, Uuid> {
-        self.authenticated.as_ref().clone()
+    /// Session tokens of every user, without the tokens
+    pub fn count_sessions_by_user(&self) -> HashMap<Uuid, usize> {
+        self.tokens.iter().map(|tokens| (*tokens.key(), tokens.len())).collect()
     }
     pub fn pending_insert(&self, server_id: String, username: String) {
-        self.pending.insert(server_id, username);
-    }
-    pub fn pending_remove(&self, server_id: &str) -> Option<(String, String)> {
-        self.pending.remove(server_id)
-    }
-    pub fn insert(&self, uuid: Uuid, token: String, userinfo: Userinfo) -> Result<(), ()> {
-        // Check for the presence of an active session.
-        if let Some(userinfo) = self.registered.get(&uuid) {
-            if let Some(token) = &userinfo.token {
-                if self.authenticated.contains_key(token) {
-                    warn!("Rejected attempt to create a second session for the same user!");
-                    return Err(())
-                }
-                debug!("`{}` already have token in registered profile (old token already removed from 'authenticated')", userinfo.nickname);
-            }
+        self.pending.insert(server_id, (username, Instant::now()));
+        AUTH_PENDING.set(self.pending.len() as i64);
+    }
+    /// Returns the username if the serverId was issued less than `ttl` ago
+    pub fn pending_remove(&self, server_id: &str, ttl: Duration) -> Option<String> {
+        let (_, (username, issued)) = self.pending.remove(server_id)?;
+        AUTH_PENDING.set(self.pending.len() as i64);
+        if issued.elapsed() > ttl {
+            AUTH_PENDING_EXPIRED.inc();
+            return None
         }
-
-        // Adding a user
-        self.authenticated.insert(token, uuid);
+        Some(username)
+    }
+    /// Drops authentications older than `ttl`, returns how many were removed
+    pub fn purge_pending(&self, ttl: Duration) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|_, (_, issued)| issued.elapsed() <= ttl);
+        let expired = before.saturating_sub(self.pending.len());
+        AUTH_PENDING_EXPIRED.inc_by(expired as u64);
+        AUTH_PENDING.set(self.pending.len() as i64);
+        expired
+    }
+    /// Registers a new session token, the session policy is up to the caller
+    pub fn insert(&self, uuid: Uuid, token: String, userinfo: Userinfo, lifetime: Duration) {
+        self.tokens.entry(uuid).or_default().insert(token.clone());
+        self.authenticated.insert(token, AuthSession { uuid, expires: Instant::now() + lifetime });
         self.insert_user(uuid, userinfo);
-        Ok(())
     }
     pub fn insert_user(&self, uuid: Uuid, userinfo: Userinfo) {
         // self.registered.insert(uuid, userinfo)
         let usercopy = userinfo.clone();
-        self.registered.entry(uuid)
+        let user = self.registered.entry(uuid)
             .and_modify(|exist| {
                 if !userinfo.nickname.is_empty() { exist.nickname = userinfo.nickname };
                 if !userinfo.auth_provider.is_empty() { exist.auth_provider = userinfo.auth_provider };
                 if userinfo.rank != Userinfo::default().rank { exist.rank = userinfo.rank };
                 if userinfo.token.is_some() { exist.token = userinfo.token };
                 if userinfo.version != Userinfo::default().version { exist.version = userinfo.version };
+                if !userinfo.properties.is_empty() { exist.properties = userinfo.properties };
                 exist.last_used = userinfo.last_used;
             }).or_insert(usercopy);
+        self.persist(&user);
     }
     pub fn get(
         &self,
         token: &String,
     ) -> Option<dashmap::mapref::one::Ref<'_, Uuid, Userinfo>> {
-        let uuid = self.authenticated.get(token)?;
-        self.registered.get(uuid.value())
+        let uuid = self.authenticated.get(token)?.uuid;
+        if self.token_expiry(token)? <= Instant::now() {
+            self.remove_token(token);
+            return None
+        }
+        self.registered.get(&uuid)
+    }
+    pub fn token_expiry(&self, token: &str) -> Option<Instant> {
+        self.authenticated.get(token).map(|session| session.expires)
+    }
+    /// Drops expired session tokens, returns how many were removed
+    pub fn purge_sessions(&self) -> usize {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.authenticated.retain(|token, session| {
+            let alive = session.expires > now;
+            if !alive {
+                expired.push((session.uuid, token.clone()));
+            }
+            alive
+        });
+        for (uuid, token) in &expired {
+            self.forget_token(uuid, token);
+        }
+        expired.len()
     }
     pub fn get_by_uuid(
         &self,
@@ -251,15 +417,43 @@ impl UManager {
     ) -> Option<dashmap::mapref::one::Ref<'_, Uuid, Userinfo>> {
         self.registered.get(uuid)
     }
+    /// Updates the nickname and remembers the previous one, returns it if the name has changed
+    pub fn rename(&self, uuid: &Uuid, nickname: &str) -> Option<String> {
+        let mut user = self.registered.get_mut(uuid)?;
+        if user.nickname == nickname || user.nickname.is_empty() {
+            return None
+        }
+        let old = std::mem::replace(&mut user.nickname, nickname.to_string());
+        user.nickname_history.push(NicknameChange {
+            nickname: old.clone(),
+            changed_at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        });
+        self.persist(&user);
+        Some(old)
+    }
     pub fn ban(&self, banned_user: &Userinfo) {
-        self.registered.entry(banned_user.uuid)
+        let user = self.registered.entry(banned_user.uuid)
             .and_modify(|exist| {
                 exist.banned = true;
             }).or_insert(banned_user.clone());
+        self.persist(&user);
+    }
+    pub fn set_rank(&self, uuid: &Uuid, rank: &str) {
+        if let Some(mut user) = self.registered.get_mut(uuid) {
+            user.rank = rank.to_string();
+            self.persist(&user);
+        };
+    }
+    pub fn set_equipped(&self, uuid: &Uuid, equipped: Vec<String>) {
+        if let Some(mut user) = self.registered.get_mut(uuid) {
+            user.equipped = equipped;
+            self.persist(&user);
+        };
     }
     pub fn unban(&self, uuid: &Uuid) {
         if let Some(mut user) = self.registered.get_mut(uuid) {
             user.banned = false;
+            self.persist(&user);
         };
     }
     pub fn _is_authenticated(&self, token: &String) -> bool {
@@ -271,14 +465,62 @@ impl UManager {
     pub fn is_banned(&self, uuid: &Uuid) -> bool {
         if let Some(user) = self.registered.get(uuid) { user.banned } else { false }
     }
+    /// Whether the user has a session token that hasn't expired yet, even without a connection
+    pub fn has_live_token(&self, uuid: &Uuid) -> bool {
+        let Some(tokens) = self.tokens.get(uuid).map(|tokens| tokens.clone()) else {
+            return false
+        };
+        let now = Instant::now();
+        tokens.iter().any(|token| self.token_expiry(token).is_some_and(|expires| expires > now))
+    }
+    /// Users with at least one session token
+    pub fn authenticated_users(&self) -> Vec<Uuid> {
+        self.tokens.iter().map(|tokens| *tokens.key()).collect()
+    }
     pub fn count_authenticated(&self) -> usize {
         self.authenticated.len()
     }
+    /// Revokes every session token of the user
     pub fn remove(&self, uuid: &Uuid) {
-        let token = self.registered.get(uuid).unwrap().token.clone().unwrap();
-        self.authenticated.remove(&token);
+        if let Some((_, tokens)) = self.tokens.remove(uuid) {
+            for token in tokens {
+                self.authenticated.remove(&token);
+            }
+        }
+    }
+    pub fn remove_token(&self, token: &str) {
+        if let Some((token, session)) = self.authenticated.remove(token) {
+            self.forget_token(&session.uuid, &token);
+        }
+    }
+    fn forget_token(&self, uuid: &Uuid, token: &str) {
+        self.tokens.remove_if_mut(uuid, |_, tokens| {
+            tokens.remove(token);
+            tokens.is_empty()
+        });
     }
 }
+
+/// Periodically removes authentications that were never verified and expired sessions
+pub async fn purge_expired_auth(user_manager: Arc<UManager>, config: Arc<tokio::sync::RwLock<Config>>) {
+    loop {
+        let ttl = Duration::from_secs(config.read().await.auth.pending_ttl);
+        tokio::time::sleep(ttl).await;
+        let expired = user_manager.purge_pending(ttl);
+        if expired > 0 {
+            debug!("Purged {expired} expired pending authentications");
+        }
+        let expired = user_manager.purge_sessions();
+        if expired > 0 {
+            debug!("Purged {expired} expired session tokens");
+        }
+    }
+}
+
+/// Opaque session token, unrelated to the serverId shown to auth providers
+pub fn new_session_token() -> String {
+    faster_hex::hex_string(&crate::utils::rand()[..32])
+}
 // End of User manager
 
 #[instrument(skip_all)]
@@ -309,4 +551,245 @@ pub async fn check_auth(
             Err(ApiError::BadRequest)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_profiles() {
+        let mojang = AuthProvider::default();
+        let profile = parse_profile(&mojang, r#"{
+            "id": "b50ad385829d3141a2167e7d7539ba7f",
+            "name": "Notch",
+            "properties": [{"name": "textures", "value": "e30=", "signature": "c2ln"}]
+        }"#).unwrap();
+        assert_eq!((profile.uuid, profile.name.as_deref()), (offline_uuid("Notch"), Some("Notch")));
+        assert_eq!(profile.properties, vec![ProfileProperty { name: "textures".to_string(), value: "e30=".to_string(), signature: Some("c2ln".to_string()) }]);
+
+        let custom = AuthProvider { uuid_pointer: "/profile/uuid".to_string(), name_pointer: "/profile/name".to_string(), ..Default::default() };
+        let profile = parse_profile(&custom, r#"{"profile": {"uuid": "b50ad385-829d-3141-a216-7e7d7539ba7f"}, "properties": "broken"}"#).unwrap();
+        assert_eq!((profile.uuid, profile.name, profile.properties), (offline_uuid("Notch"), None, vec![]));
+
+        assert!(matches!(parse_profile(&mojang, "<html>"), Err(FetchError::InvalidJson(_))));
+        assert!(matches!(parse_profile(&custom, r#"{"id": "b50ad385829d3141a2167e7d7539ba7f"}"#), Err(FetchError::MissingField(_))));
+        assert!(matches!(parse_profile(&mojang, r#"{"id": 42}"#), Err(FetchError::MissingField(_))));
+        assert!(matches!(parse_profile(&mojang, r#"{"id": "not-a-uuid"}"#), Err(FetchError::InvalidUuid(_))));
+    }
+
+    #[tokio::test]
+    async fn custom_request_mapping() {
+        use axum::{routing::post, Json, Router};
+
+        async fn has_joined(headers: axum::http::HeaderMap, Json(body): Json<HashMap<String, String>>) -> (StatusCode, String) {
+            if headers.get("x-api-key").is_some_and(|key| key == "secret") && body.get("sid").is_some_and(|sid| sid == "id") {
+                (StatusCode::OK, format!(r#"{{"data": {{"uuid": "{}"}}}}"#, offline_uuid(&body["user"])))
+            } else {
+                (StatusCode::NO_CONTENT, String::new())
+            }
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hasJoined", listener.local_addr().unwrap());
+        tokio::spawn(async { axum::serve(listener, Router::new().route("/hasJoined", post(has_joined))).await });
+
+        let mut provider = AuthProvider {
+            name: "Custom".to_string(),
+            url,
+            method: RequestMethod::Post,
+            server_id_param: "sid".to_string(),
+            username_param: "user".to_string(),
+            uuid_pointer: "/data/uuid".to_string(),
+            headers: HashMap::from([("x-api-key".to_string(), "secret".to_string())]),
+            timeout: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(fetch_json(&provider, "id", "Steve").await.unwrap().uuid, offline_uuid("Steve"));
+        provider.headers.clear();
+        assert!(matches!(fetch_json(&provider, "id", "Steve").await, Err(FetchError::WrongResponse(204, _))));
+    }
+
+    #[test]
+    fn offline_uuids() {
+        assert_eq!(offline_uuid("Notch").to_string(), "b50ad385-829d-3141-a216-7e7d7539ba7f");
+        assert_eq!(offline_uuid("Notch").get_version_num(), 3);
+    }
+
+    #[tokio::test]
+    async fn offline_provider_allowlist() {
+        let provider = AuthProvider {
+            name: "LAN".to_string(),
+            kind: AuthProviderKind::Offline,
+            allowlist: vec!["Steve".to_string()],
+            ..Default::default()
+        };
+        let providers = AuthProviders(vec![provider.clone()]);
+        let profile = has_joined(providers.clone(), &AuthConfig::default(), Default::default(), "id", "steve").await.unwrap().unwrap();
+        assert_eq!((profile.uuid, profile.name.as_deref(), profile.provider), (offline_uuid("Steve"), Some("Steve"), provider));
+        assert_eq!(has_joined(providers, &AuthConfig::default(), Default::default(), "id", "Alex").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ordered_strategy_respects_namespaces() {
+        let offline = |name: &str, usernames: &[&str]| AuthProvider {
+            name: name.to_string(),
+            kind: AuthProviderKind::Offline,
+            usernames: usernames.iter().map(|u| u.to_string()).collect(),
+            ..Default::default()
+        };
+        let providers = AuthProviders(vec![offline("Bots", &["bot_*"]), offline("First", &[]), offline("Second", &[])]);
+        let config = AuthConfig { strategy: AuthStrategy::Ordered, ..Default::default() };
+
+        let profile = has_joined(providers.clone(), &config, Default::default(), "id", "Steve").await.unwrap().unwrap();
+        assert_eq!(profile.provider.name, "First");
+        let profile = has_joined(providers, &config, Default::default(), "id", "bot_1").await.unwrap().unwrap();
+        assert_eq!(profile.provider.name, "Bots");
+    }
+
+    #[tokio::test]
+    async fn providers_confirm_only_own_uuids() {
+        let offline = |name: &str, uuids: &[&str]| AuthProvider {
+            name: name.to_string(),
+            kind: AuthProviderKind::Offline,
+            uuids: uuids.iter().map(|u| u.to_string()).collect(),
+            ..Default::default()
+        };
+        // Offline-mode (v3) UUIDs belong to LAN only, the rogue provider can't claim them
+        let providers = AuthProviders(vec![offline("Rogue", &["v4"]), offline("LAN", &["v3"])]);
+        for strategy in [AuthStrategy::Ordered, AuthStrategy::Race] {
+            let config = AuthConfig { strategy, ..Default::default() };
+            let profile = has_joined(providers.clone(), &config, Default::default(), "id", "Steve").await.unwrap().unwrap();
+            assert_eq!(profile.provider.name, "LAN");
+        }
+
+        let providers = AuthProviders(vec![offline("Rogue", &["v4"])]);
+        let health = Arc::new(ProvidersHealth::default());
+        assert_eq!(has_joined(providers, &AuthConfig::default(), Arc::clone(&health), "id", "Steve").await.unwrap(), None);
+        // Misbehaving, but not broken
+        assert!(health.is_available("Rogue"));
+    }
+
+    #[tokio::test]
+    async fn open_circuit_is_not_an_error() {
+        let breaker = CircuitBreakerConfig::default();
+        let health = Arc::new(ProvidersHealth::default());
+        for _ in 0..breaker.threshold {
+            health.record("Broken", Outcome::Failure, Duration::ZERO, None, &breaker);
+        }
+        assert!(!health.is_available("Broken"));
+
+        let broken = AuthProvider { name: "Broken".to_string(), url: "http://127.0.0.1:1/hasJoined".to_string(), ..Default::default() };
+        // Doesn't know the player
+        let lan = AuthProvider { name: "LAN".to_string(), kind: AuthProviderKind::Offline, allowlist: vec!["Alex".to_string()], ..Default::default() };
+        for strategy in [AuthStrategy::Ordered, AuthStrategy::Race] {
+            let config = AuthConfig { strategy, ..Default::default() };
+            let providers = AuthProviders(vec![broken.clone(), lan.clone()]);
+            assert_eq!(has_joined(providers, &config, Arc::clone(&health), "id", "Steve").await.unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn pending_expires() {
+        let manager = UManager::new();
+        manager.pending_insert("fresh".to_string(), "Alice".to_string());
+        manager.pending_insert("stale".to_string(), "Bob".to_string());
+        manager.pending.get_mut("stale").unwrap().1 -= Duration::from_secs(120);
+
+        assert_eq!(manager.purge_pending(Duration::from_secs(60)), 1);
+        assert_eq!(manager.pending_remove("stale", Duration::from_secs(60)), None);
+
+        manager.pending_insert("stale".to_string(), "Bob".to_string());
+        manager.pending.get_mut("stale").unwrap().1 -= Duration::from_secs(120);
+        assert_eq!(manager.pending_remove("stale", Duration::from_secs(60)), None);
+        assert!(!manager.pending.contains_key("stale"));
+        assert_eq!(manager.pending_remove("fresh", Duration::from_secs(60)), Some("Alice".to_string()));
+    }
+
+    #[test]
+    fn session_token_expires() {
+        let manager = UManager::new();
+        let (alice, bob) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let alice_token = new_session_token();
+        assert_ne!(alice_token, new_session_token());
+        manager.insert(alice, alice_token.clone(), Userinfo { uuid: alice, ..Default::default() }, Duration::from_secs(60));
+        manager.insert(bob, "bob".to_string(), Userinfo { uuid: bob, ..Default::default() }, Duration::ZERO);
+
+        assert_eq!(manager.get(&alice_token).unwrap().uuid, alice);
+        assert!(manager.get(&"bob".to_string()).is_none());
+        assert!(manager.token_expiry("bob").is_none());
+
+        manager.authenticated.get_mut(&alice_token).unwrap().expires = Instant::now();
+        assert_eq!(manager.purge_sessions(), 1);
+        assert_eq!(manager.count_authenticated(), 0);
+        assert!(manager.tokens.is_empty());
+    }
+
+    #[test]
+    fn last_change_is_stored() {
+        let folder = std::env::temp_dir().join(format!("sculptor-users-{}", Uuid::from_u128(rand::random())));
+        let manager = UManager::with_store(UserStore::new(&folder)).unwrap();
+        let alice = Uuid::from_u128(1);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let manager = &manager;
+                scope.spawn(move || {
+                    for change in 0..50 {
+                        manager.insert_user(alice, Userinfo { uuid: alice, rank: format!("{thread}-{change}"), ..Default::default() });
+                    }
+                });
+            }
+        });
+        let rank = manager.get_by_uuid(&alice).unwrap().rank.clone();
+        drop(manager);
+
+        // The writer thread finishes the queue after the manager is gone
+        let store = UserStore::new(&folder);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while store.load().unwrap().first().is_none_or(|user| user.rank != rank) {
+            assert!(std::time::Instant::now() < deadline, "stored rank isn't the last one");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // A stale snapshot queued after the last one would overwrite it
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.load().unwrap()[0].rank, rank);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn nickname_history() {
+        let manager = UManager::new();
+        let alice = Uuid::from_u128(1);
+        assert_eq!(manager.rename(&alice, "Alice"), None);
+        manager.insert_user(alice, Userinfo { uuid: alice, nickname: "Alice".to_string(), ..Default::default() });
+        assert_eq!(manager.rename(&alice, "Alice"), None);
+        assert_eq!(manager.rename(&alice, "Alicia"), Some("Alice".to_string()));
+
+        let user = manager.get_by_uuid(&alice).unwrap();
+        assert_eq!(user.nickname, "Alicia");
+        assert_eq!(user.nickname_history.iter().map(|change| change.nickname.as_str()).collect::<Vec<_>>(), ["Alice"]);
+    }
+
+    #[test]
+    fn multiple_tokens() {
+        let manager = UManager::new();
+        let alice = Uuid::from_u128(1);
+        for token in ["first", "second", "third"] {
+            manager.insert(alice, token.to_string(), Userinfo { uuid: alice, ..Default::default() }, Duration::from_secs(60));
+        }
+        let bob = Uuid::from_u128(2);
+        manager.insert(bob, "bob".to_string(), Userinfo { uuid: bob, ..Default::default() }, Duration::from_secs(60));
+        manager.remove_token("first");
+        assert!(manager.get(&"second".to_string()).is_some());
+        assert_eq!(manager.tokens.get(&alice).unwrap().len(), 2);
+        manager.remove(&alice);
+        assert_eq!(manager.count_authenticated(), 1);
+        assert!(manager.get(&"bob".to_string()).is_some());
+        assert!(!manager.tokens.contains_key(&alice));
+        assert_eq!(manager.count_sessions_by_user(), HashMap::from([(bob, 1)]));
+
+        assert!(manager.has_live_token(&bob));
+        assert!(!manager.has_live_token(&alice));
+        let carol = Uuid::from_u128(3);
+        manager.insert(carol, "expired".to_string(), Userinfo { uuid: carol, ..Default::default() }, Duration::ZERO);
+        assert!(!manager.has_live_token(&carol));
+    }
+}
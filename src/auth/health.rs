@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{AUTH_PROVIDER_LATENCY, AUTH_PROVIDER_REQUESTS};
+
+use super::AuthProvider;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row after which the provider is skipped
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+    /// Seconds the provider is skipped for
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self { threshold: default_threshold(), cooldown: default_cooldown() }
+    }
+}
+
+fn default_threshold() -> u32 {
+    3
+}
+
+fn default_cooldown() -> u64 {
+    60
+}
+
+/// Result of a single request to a provider
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Player is authenticated
+    Success,
+    /// Provider works, but doesn't know the player
+    Miss,
+    /// Provider is unreachable or answered with garbage
+    Failure,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Miss => "miss",
+            Outcome::Failure => "failure",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Provider is used as usual
+    Closed,
+    /// Provider is skipped until the cooldown ends
+    Open,
+    /// Cooldown ended, the next request decides while others keep skipping the provider
+    HalfOpen,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Health {
+    successes: u64,
+    misses: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+    /// When the only request allowed in the half-open state was sent
+    probe_started: Option<Instant>,
+    last_latency: Option<Duration>,
+    last_error: Option<String>,
+}
+
+impl Health {
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.open_until {
+            Some(until) if until > now => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+            None => CircuitState::Closed,
+        }
+    }
+}
+
+/// Provider state for the admin API
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderStatus {
+    pub name: String,
+    pub url: String,
+    pub state: CircuitState,
+    pub successes: u64,
+    pub misses: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    /// Seconds until the provider is tried again
+    pub retry_in: Option<u64>,
+    pub last_latency_ms: Option<u128>,
+    pub last_error: Option<String>,
+}
+
+/// Tracks how auth providers behave and opens the circuit for failing ones
+#[derive(Debug, Default)]
+pub struct ProvidersHealth(DashMap<String, Health>);
+
+impl ProvidersHealth {
+    /// False while the circuit of the provider is open
+    pub fn is_available(&self, provider: &str) -> bool {
+        self.0.get(provider).is_none_or(|health| health.state(Instant::now()) != CircuitState::Open)
+    }
+
+    /// Decides whether a request may be sent to the provider. After the cooldown only one probe is let through,
+    /// until it's recorded. A probe that was never recorded (e.g. cancelled) is replaced after another cooldown.
+    pub fn try_acquire(&self, provider: &str, breaker: &CircuitBreakerConfig) -> bool {
+        let Some(mut health) = self.0.get_mut(provider) else { return true };
+        let now = Instant::now();
+        match health.state(now) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                let cooldown = Duration::from_secs(breaker.cooldown);
+                if health.probe_started.is_some_and(|started| now.duration_since(started) < cooldown) {
+                    return false;
+                }
+                health.probe_started = Some(now);
+                true
+            },
+        }
+    }
+
+    pub fn record(&self, provider: &str, outcome: Outcome, latency: Duration, error: Option<String>, breaker: &CircuitBreakerConfig) {
+        AUTH_PROVIDER_REQUESTS.with_label_values(&[provider, outcome.label()]).inc();
+        AUTH_PROVIDER_LATENCY.with_label_values(&[provider]).observe(latency.as_secs_f64());
+
+        let mut health = self.0.entry(provider.to_string()).or_default();
+        health.last_latency = Some(latency);
+        health.probe_started = None;
+        match outcome {
+            Outcome::Success | Outcome::Miss => {
+                if outcome == Outcome::Success { health.successes += 1 } else { health.misses += 1 }
+                if health.open_until.is_some() {
+                    tracing::info!("Auth provider {provider} is back");
+                }
+                health.consecutive_failures = 0;
+                health.open_until = None;
+            },
+            Outcome::Failure => {
+                health.failures += 1;
+                health.consecutive_failures += 1;
+                health.last_error = error;
+                // A failed probe after the cooldown opens the circuit again right away
+                if health.consecutive_failures >= breaker.threshold {
+                    tracing::warn!("Auth provider {provider} failed {} times in a row, skipping it for {}s", health.consecutive_failures, breaker.cooldown);
+                    health.open_until = Some(Instant::now() + Duration::from_secs(breaker.cooldown));
+                }
+            },
+        }
+    }
+
+    pub fn status(&self, providers: &[AuthProvider]) -> Vec<ProviderStatus> {
+        let now = Instant::now();
+        providers.iter().map(|provider| {
+            let health = self.0.get(&provider.name).map(|health| health.clone()).unwrap_or_default();
+            ProviderStatus {
+                name: provider.name.clone(),
+                url: provider.url.clone(),
+                state: health.state(now),
+                successes: health.successes,
+                misses: health.misses,
+                failures: health.failures,
+                consecutive_failures: health.consecutive_failures,
+                retry_in: health.open_until.filter(|until| *until > now).map(|until| (until - now).as_secs()),
+                last_latency_ms: health.last_latency.map(|latency| latency.as_millis()),
+                last_error: health.last_error,
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_breaker() {
+        let health = ProvidersHealth::default();
+        let breaker = CircuitBreakerConfig { threshold: 2, cooldown: 60 };
+        let record = |outcome| health.record("Flaky", outcome, Duration::from_millis(5), Some("timeout".to_string()), &breaker);
+
+        record(Outcome::Failure);
+        assert!(health.is_available("Flaky"));
+        record(Outcome::Miss);
+        record(Outcome::Failure);
+        assert!(health.is_available("Flaky"));
+        record(Outcome::Failure);
+        assert!(!health.is_available("Flaky"));
+
+        let provider = AuthProvider { name: "Flaky".to_string(), ..Default::default() };
+        let status = &health.status(std::slice::from_ref(&provider))[0];
+        assert_eq!((status.state, status.failures, status.misses), (CircuitState::Open, 3, 1));
+        assert_eq!(status.last_error.as_deref(), Some("timeout"));
+
+        // Cooldown is over, only one request probes the provider
+        health.0.get_mut("Flaky").unwrap().open_until = Some(Instant::now());
+        assert!(health.is_available("Flaky"));
+        assert_eq!(health.status(std::slice::from_ref(&provider))[0].state, CircuitState::HalfOpen);
+        assert!(health.try_acquire("Flaky", &breaker));
+        assert!(!health.try_acquire("Flaky", &breaker));
+        // The probe failed, skipping again
+        record(Outcome::Failure);
+        assert!(!health.try_acquire("Flaky", &breaker));
+
+        health.0.get_mut("Flaky").unwrap().open_until = Some(Instant::now());
+        assert!(health.try_acquire("Flaky", &breaker));
+        // The probe got lost
+        health.0.get_mut("Flaky").unwrap().probe_started = Some(Instant::now() - Duration::from_secs(61));
+        assert!(health.try_acquire("Flaky", &breaker));
+        record(Outcome::Success);
+        assert!(health.try_acquire("Flaky", &breaker));
+        assert!(health.try_acquire("Unknown", &breaker));
+        assert_eq!(health.status(&[provider])[0].state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn single_half_open_probe() {
+        let health = ProvidersHealth::default();
+        let breaker = CircuitBreakerConfig::default();
+        health.0.insert("Flaky".to_string(), Health { open_until: Some(Instant::now()), ..Default::default() });
+
+        let acquired = std::thread::scope(|scope| {
+            let probes: Vec<_> = (0..8).map(|_| scope.spawn(|| health.try_acquire("Flaky", &breaker))).collect();
+            probes.into_iter().filter_map(|probe| probe.join().unwrap().then_some(())).count()
+        });
+        assert_eq!(acquired, 1);
+    }
+}
@@ -0,0 +1,319 @@
+use std::{fmt, fs, net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr, sync::RwLock as StdRwLock};
+
+use anyhow::Context;
+use axum::{extract::{ConnectInfo, FromRequestParts, Request, State}, http::{request::Parts, HeaderMap}, middleware::Next, response::Response};
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{ApiError, ApiResult, AppState, IP_BANS_REJECTED};
+
+/// Single address or CIDR range, like `10.0.0.1` or `10.0.0.0/8`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange(IpNet);
+
+impl IpRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(|ip| IpNet::from(ip.to_canonical())))
+            .map(|net| Self(net.trunc()))
+            .map_err(|_| format!("{s} is not an IP address or CIDR range"))
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(val: IpRange) -> Self {
+        val.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Single addresses are shown without the prefix
+        if self.0.prefix_len() == self.0.max_prefix_len() {
+            write!(f, "{}", self.0.addr())
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Finds the address of the client. Forwarding headers are trusted only when the peer is a trusted proxy.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpRange]) -> IpAddr {
+    let peer = peer.to_canonical();
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|range| range.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    // Every proxy appends the address it got the request from, so the first untrusted one from the right is the client
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+    if let Some(ip) = forwarded.iter().rev().find(|ip| !is_trusted(ip)).or(forwarded.first()) {
+        return *ip;
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map_or(peer, |ip| ip.to_canonical())
+}
+
+/// Address of the client, see [`client_ip`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = parts.extensions.get::<ConnectInfo<SocketAddr>>().copied().ok_or_else(|| {
+            tracing::error!("Peer address is unavailable, the server must be started with connect info!");
+            ApiError::Internal
+        })?;
+        let trusted_proxies = &state.config.read().await.network.trusted_proxies;
+        Ok(Self(client_ip(peer.ip(), &parts.headers, trusted_proxies)))
+    }
+}
+
+/// Lists of all banned ranges for the admin API
+#[derive(Debug, Serialize)]
+pub struct IpBanList {
+    pub config: Vec<IpRange>,
+    pub admin: Vec<IpRange>,
+    pub minecraft: Vec<IpRange>,
+}
+
+/// Banned IP ranges. Ranges from the config are passed on every check, since the config can be reloaded.
+#[derive(Debug, Default)]
+pub struct IpBans {
+    /// Added with the admin API, kept in `file`
+    admin: StdRwLock<Vec<IpRange>>,
+    /// From banned-ips.json of the Minecraft server, with the end of the ban
+    minecraft: StdRwLock<Vec<(IpRange, Option<DateTime<Utc>>)>>,
+    file: Option<PathBuf>,
+    /// Writes `file` one at a time, so an older list can't overwrite a newer one
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl IpBans {
+    pub fn with_file(file: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let file = file.into();
+        let admin = if file.exists() {
+            let data = fs::read_to_string(&file).with_context(|| format!("Can't read {file:?}"))?;
+            serde_json::from_str(&data).with_context(|| format!("Can't parse {file:?}"))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self { admin: StdRwLock::new(admin), file: Some(file), ..Default::default() })
+    }
+
+    /// First range containing the address
+    pub fn find(&self, ip: &IpAddr, config: &[IpRange]) -> Option<IpRange> {
+        let admin = self.admin.read().unwrap();
+        let minecraft = self.active_minecraft();
+        config.iter().chain(admin.iter()).chain(minecraft.iter()).find(|range| range.contains(ip)).copied()
+    }
+
+    /// Returns false if the range was already banned
+    pub async fn ban(&self, range: IpRange) -> anyhow::Result<bool> {
+        {
+            let mut admin = self.admin.write().unwrap();
+            if admin.contains(&range) {
+                return Ok(false);
+            }
+            admin.push(range);
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    /// Returns false if the range wasn't banned
+    pub async fn unban(&self, range: &IpRange) -> anyhow::Result<bool> {
+        {
+            let mut admin = self.admin.write().unwrap();
+            let len = admin.len();
+            admin.retain(|banned| banned != range);
+            if admin.len() == len {
+                return Ok(false);
+            }
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    pub fn set_minecraft(&self, ranges: Vec<(IpRange, Option<DateTime<Utc>>)>) {
+        *self.minecraft.write().unwrap() = ranges;
+    }
+
+    pub fn list(&self, config: &[IpRange]) -> IpBanList {
+        IpBanList {
+            config: config.to_vec(),
+            admin: self.admin.read().unwrap().clone(),
+            minecraft: self.active_minecraft(),
+        }
+    }
+
+    /// Expired bans stay in banned-ips.json until the Minecraft server removes them
+    fn active_minecraft(&self) -> Vec<IpRange> {
+        let now = Utc::now();
+        self.minecraft.read().unwrap().iter().filter(|(_, expires)| expires.is_none_or(|expires| expires > now)).map(|(range, _)| *range).collect()
+    }
+
+    /// Writes the current admin list to `file` without blocking the runtime
+    async fn save(&self) -> anyhow::Result<()> {
+        let Some(file) = &self.file else { return Ok(()) };
+        // The list is taken after the previous save has finished, so the last write is always the newest one
+        let _saving = self.saving.lock().await;
+        let data = serde_json::to_vec_pretty(&*self.admin.read().unwrap())?;
+        let file = file.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Some(folder) = file.parent() {
+                fs::create_dir_all(folder)?;
+            }
+            let tmp = file.with_extension("json.tmp");
+            fs::write(&tmp, data).with_context(|| format!("Can't write {tmp:?}"))?;
+            fs::rename(&tmp, &file).with_context(|| format!("Can't replace {file:?}"))?;
+            Ok(())
+        }).await?
+    }
+}
+
+/// Middleware for routes banned addresses must not reach
+pub async fn reject_banned_ips(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> ApiResult<Response> {
+    let banned = state.ip_bans.find(&ip, &state.config.read().await.network.banned_ips);
+    if let Some(range) = banned {
+        IP_BANS_REJECTED.inc();
+        info!("Rejected {} from {ip}, banned by {range}", request.uri().path());
+        return Err(ApiError::Forbidden);
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        assert!(range("10.0.0.0/8").contains(&ip("10.1.2.3")));
+        assert!(!range("10.0.0.0/8").contains(&ip("11.0.0.1")));
+        assert!(range("192.168.1.5").contains(&ip("::ffff:192.168.1.5")));
+        assert!(range("2001:db8::/32").contains(&ip("2001:db8::1")));
+        assert_eq!(range("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(range("1.2.3.4").to_string(), "1.2.3.4");
+        assert!("not an ip".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn forwarded_only_from_trusted_proxies() {
+        let proxies = [range("10.0.0.0/8")];
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6, 1.2.3.4, 10.0.0.2"));
+        headers.insert("x-real-ip", HeaderValue::from_static("5.5.5.5"));
+
+        // Anyone can send the headers
+        assert_eq!(client_ip(ip("8.8.8.8"), &headers, &proxies), ip("8.8.8.8"));
+        // The leftmost address is chosen by the client and can't be trusted
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &proxies), ip("1.2.3.4"));
+
+        headers.remove("x-forwarded-for");
+        assert_eq!(client_ip(ip("10.0.0.1"), &headers, &proxies), ip("5.5.5.5"));
+        assert_eq!(client_ip(ip("10.0.0.1"), &HeaderMap::new(), &proxies), ip("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn bans() {
+        let bans = IpBans::default();
+        let config = [range("1.1.1.0/24")];
+        assert_eq!(bans.find(&ip("1.1.1.7"), &config), Some(config[0]));
+
+        assert!(bans.ban(range("2.2.0.0/16")).await.unwrap());
+        assert!(!bans.ban(range("2.2.0.0/16")).await.unwrap());
+        bans.set_minecraft(vec![(range("3.3.3.3"), None)]);
+        assert!(bans.find(&ip("2.2.9.9"), &[]).is_some());
+        assert!(bans.find(&ip("3.3.3.3"), &[]).is_some());
+        assert!(bans.find(&ip("4.4.4.4"), &config).is_none());
+
+        assert!(bans.unban(&range("2.2.0.0/16")).await.unwrap());
+        assert!(bans.find(&ip("2.2.9.9"), &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn bans_are_saved() {
+        let file = std::env::temp_dir().join(format!("sculptor-ip-bans-{}", uuid::Uuid::from_u128(rand::random()))).join("ip-bans.json");
+        let bans = Arc::new(IpBans::with_file(&file).unwrap());
+        let tasks: Vec<_> = (0..8u8).map(|i| {
+            let bans = Arc::clone(&bans);
+            tokio::spawn(async move { bans.ban(range(&format!("10.0.0.{i}"))).await.unwrap() })
+        }).collect();
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+        assert!(bans.unban(&range("10.0.0.0")).await.unwrap());
+
+        let saved = IpBans::with_file(&file).unwrap();
+        assert_eq!(saved.list(&[]).admin.len(), 7);
+        assert!(saved.find(&ip("10.0.0.7"), &[]).is_some());
+        std::fs::remove_dir_all(file.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn minecraft_bans_expire() {
+        let entry = |expires: Option<&str>| crate::state::BannedIp { ip: range("5.5.5.5"), expires: expires.map(str::to_string) };
+        assert_eq!(entry(None).expires_at(), None);
+        assert_eq!(entry(Some("forever")).expires_at(), None);
+        assert_eq!(entry(Some("garbage")).expires_at(), None);
+        let expired = entry(Some("2020-01-01 00:00:00 +0300")).expires_at();
+        assert_eq!(expired, Some(DateTime::parse_from_rfc3339("2019-12-31T21:00:00Z").unwrap().to_utc()));
+
+        let bans = IpBans::default();
+        bans.set_minecraft(vec![(range("5.5.5.5"), expired), (range("6.6.6.6"), Some(Utc::now() + chrono::Duration::hours(1)))]);
+        assert!(bans.find(&ip("5.5.5.5"), &[]).is_none());
+        assert!(bans.find(&ip("6.6.6.6"), &[]).is_some());
+        assert_eq!(bans.list(&[]).minecraft, [range("6.6.6.6")]);
+    }
+}
@@ -1,5 +1,11 @@
 mod auth;
 mod types;
+mod store;
+mod health;
+mod ip;
 
 pub use auth::*;
+pub use health::*;
+pub use ip::*;
 pub use types::*;
+pub use store::*;
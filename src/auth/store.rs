@@ -0,0 +1,140 @@
+use std::{fs, path::PathBuf, sync::mpsc};
+
+use anyhow::Context;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use super::Userinfo;
+
+/// Durable storage for registered users.
+/// Every user is kept in its own `<uuid>.json` file, so a write touches only one small file.
+#[derive(Debug)]
+pub struct UserStore {
+    folder: PathBuf,
+}
+
+impl UserStore {
+    pub fn new(folder: impl Into<PathBuf>) -> Self {
+        Self { folder: folder.into() }
+    }
+
+    /// Reads all stored users. Broken files are skipped with a warning.
+    pub fn load(&self) -> anyhow::Result<Vec<Userinfo>> {
+        fs::create_dir_all(&self.folder).with_context(|| format!("Can't create users folder {:?}", self.folder))?;
+
+        let mut users = Vec::new();
+        for entry in fs::read_dir(&self.folder)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let parsed = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| serde_json::from_str::<Userinfo>(&data).map_err(anyhow::Error::from));
+            match parsed {
+                Ok(mut user) => {
+                    // Files written by older versions may still have it
+                    user.token = None;
+                    users.push(user);
+                },
+                Err(e) => warn!("Skipping broken user file {path:?} due: {e:?}"),
+            }
+        }
+        debug!("Loaded {} users from {:?}", users.len(), self.folder);
+        Ok(users)
+    }
+
+    /// Atomically writes the user: data goes to a temporary file which is then renamed over the old one.
+    pub fn save(&self, user: &Userinfo) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(user)?;
+
+        let path = self.path(&user.uuid);
+        // Unique, so a concurrent writer never renames someone else's half-written file
+        let tmp = path.with_extension(format!("json.{:x}.tmp", rand::random::<u64>()));
+        fs::write(&tmp, data).with_context(|| format!("Can't write {tmp:?}"))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Can't replace {path:?}"))?;
+        Ok(())
+    }
+
+    fn path(&self, uuid: &Uuid) -> PathBuf {
+        self.folder.join(format!("{}.json", uuid.as_hyphenated()))
+    }
+}
+
+/// Saves users on its own thread, one at a time and in the order of changes, so the async runtime never waits for the disk
+#[derive(Debug, Clone)]
+pub struct UserWriter(mpsc::Sender<Userinfo>);
+
+impl UserWriter {
+    pub fn new(store: UserStore) -> Self {
+        let (tx, rx) = mpsc::channel::<Userinfo>();
+        std::thread::Builder::new()
+            .name(String::from("user-store"))
+            .spawn(move || {
+                for user in rx {
+                    if let Err(e) = store.save(&user) {
+                        error!("Can't save user {} due: {e:?}", user.uuid);
+                    }
+                }
+            })
+            .expect("Can't start the user store thread");
+        Self(tx)
+    }
+
+    pub fn save(&self, user: Userinfo) {
+        if self.0.send(user).is_err() {
+            error!("User store thread is gone, changes are not saved!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let folder = std::env::temp_dir().join(format!("sculptor-users-{}", Uuid::from_u128(rand::random())));
+        let store = UserStore::new(&folder);
+        let user = Userinfo {
+            uuid: Uuid::from_u128(rand::random()),
+            nickname: "Steve".to_string(),
+            rank: "supporter".to_string(),
+            token: Some("secret".to_string()),
+            ..Default::default()
+        };
+        assert!(store.load().unwrap().is_empty());
+        store.save(&user).unwrap();
+        std::fs::write(folder.join("broken.json"), "{").unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].nickname, "Steve");
+        assert_eq!(loaded[0].rank, "supporter");
+        assert_eq!(loaded[0].token, None);
+        assert!(!std::fs::read_to_string(store.path(&user.uuid)).unwrap().contains("secret"));
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn writer_keeps_order() {
+        let folder = std::env::temp_dir().join(format!("sculptor-users-{}", Uuid::from_u128(rand::random())));
+        let store = UserStore::new(&folder);
+        store.load().unwrap();
+        let writer = UserWriter::new(UserStore::new(&folder));
+        let uuid = Uuid::from_u128(rand::random());
+        for rank in ["first", "second", "last"] {
+            writer.save(Userinfo { uuid, rank: rank.to_string(), ..Default::default() });
+        }
+        drop(writer);
+
+        // The thread finishes the queue after the sender is gone
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while store.load().unwrap().first().is_none_or(|user| user.rank != "last") {
+            assert!(std::time::Instant::now() < deadline, "user wasn't saved");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
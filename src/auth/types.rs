@@ -1,18 +1,57 @@
+use std::collections::HashMap;
+
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", default)]
 pub struct Userinfo {
     pub uuid: Uuid,
     pub nickname: String,
     pub rank: String,
     pub last_used: String,
     pub auth_provider: AuthProvider,
+    /// Never shown or stored, it would let anyone who reads it act as the player
+    #[serde(skip_serializing)]
     pub token: Option<String>,
     pub version: String,
-    pub banned: bool
+    pub banned: bool,
+    /// Avatar slots shown to other players
+    pub equipped: Vec<String>,
+    /// Skin, cape and other data returned by the auth provider
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub properties: Vec<ProfileProperty>,
+    /// Previous nicknames, oldest first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub nickname_history: Vec<NicknameChange>,
+}
+
+/// Signed profile property, like `textures`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NicknameChange {
+    pub nickname: String,
+    /// When the player stopped using it
+    pub changed_at: String,
+}
+
+/// Player confirmed by an auth provider
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinedProfile {
+    pub uuid: Uuid,
+    /// Canonical name, if the provider returned one
+    pub name: Option<String>,
+    pub properties: Vec<ProfileProperty>,
+    pub provider: AuthProvider,
 }
 
 impl Default for Userinfo {
@@ -25,7 +64,10 @@ impl Default for Userinfo {
             auth_provider: Default::default(),
             token: Default::default(),
             version: "0.1.4+1.20.1".to_string(),
-            banned: false
+            banned: false,
+            equipped: vec![crate::storage::DEFAULT_AVATAR_ID.to_string()],
+            properties: Vec::new(),
+            nickname_history: Vec::new(),
         }
     }
 }
@@ -36,14 +78,99 @@ impl Default for Userinfo {
 #[serde(rename_all = "camelCase")]
 pub struct AuthProvider {
     pub name: String,
+    #[serde(default)]
     pub url: String,
+    #[serde(default)]
+    pub kind: AuthProviderKind,
+    /// Usernames accepted by the offline provider, anyone if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowlist: Vec<String>,
+    /// Username patterns (`*` is a wildcard) this provider is asked about, any if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usernames: Vec<String>,
+    /// UUIDs this provider may confirm, any if empty: a version like `v3`, or a pattern of the hyphenated UUID (`*` is a wildcard)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uuids: Vec<String>,
+    // Request and response mapping for servers that differ from Mojang's.
+    // Not stored with users, headers may contain secrets.
+    /// GET sends parameters in the query string, POST as a JSON object
+    #[serde(default, skip_serializing)]
+    pub method: RequestMethod,
+    #[serde(default = "default_server_id_param", skip_serializing)]
+    pub server_id_param: String,
+    #[serde(default = "default_username_param", skip_serializing)]
+    pub username_param: String,
+    /// JSON pointer to the player's UUID in the response
+    #[serde(default = "default_uuid_pointer", skip_serializing)]
+    pub uuid_pointer: String,
+    /// JSON pointer to the player's name in the response
+    #[serde(default = "default_name_pointer", skip_serializing)]
+    pub name_pointer: String,
+    /// JSON pointer to the profile properties (skin, cape) in the response
+    #[serde(default = "default_properties_pointer", skip_serializing)]
+    pub properties_pointer: String,
+    #[serde(default, skip_serializing)]
+    pub headers: HashMap<String, String>,
+    /// Seconds, global timeout if not set
+    #[serde(default, skip_serializing)]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RequestMethod {
+    #[default]
+    Get,
+    Post,
+}
+
+fn default_server_id_param() -> String {
+    "serverId".to_string()
+}
+
+fn default_username_param() -> String {
+    "username".to_string()
+}
+
+fn default_uuid_pointer() -> String {
+    "/id".to_string()
+}
+
+fn default_name_pointer() -> String {
+    "/name".to_string()
+}
+
+fn default_properties_pointer() -> String {
+    "/properties".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthProviderKind {
+    /// Mojang-compatible session server
+    #[default]
+    Http,
+    /// No verification at all, for LAN and testing. UUIDs are derived like in offline-mode servers
+    Offline,
 }
 
 impl Default for AuthProvider {
     fn default() -> Self {
         Self {
             name: "Unknown".to_string(),
-            url: Default::default()
+            url: Default::default(),
+            kind: Default::default(),
+            allowlist: Default::default(),
+            usernames: Default::default(),
+            uuids: Default::default(),
+            method: Default::default(),
+            server_id_param: default_server_id_param(),
+            username_param: default_username_param(),
+            uuid_pointer: default_uuid_pointer(),
+            name_pointer: default_name_pointer(),
+            properties_pointer: default_properties_pointer(),
+            headers: Default::default(),
+            timeout: None,
         }
     }
 }
@@ -52,15 +179,132 @@ impl AuthProvider {
     pub fn is_empty(&self) -> bool {
         self.name == "Unknown"
     }
+    /// Checks the username against the provider namespace
+    pub fn accepts(&self, username: &str) -> bool {
+        self.usernames.is_empty() || self.usernames.iter().any(|pattern| wildcard_match(pattern, username))
+    }
+    /// Checks the UUID confirmed by the provider against its namespace
+    pub fn owns(&self, uuid: &Uuid) -> bool {
+        let hyphenated = uuid.as_hyphenated().to_string();
+        self.uuids.is_empty() || self.uuids.iter().any(|rule| match rule.strip_prefix(['v', 'V']).and_then(|v| v.parse::<usize>().ok()) {
+            Some(version) => uuid.get_version_num() == version,
+            None => wildcard_match(rule, &hyphenated),
+        })
+    }
+}
+
+/// Case-insensitive match where `*` stands for any sequence of characters
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None; // (position of the last `*`, text position it currently covers up to)
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, covered)) = backtrack {
+            backtrack = Some((star, covered + 1));
+            p = star + 1;
+            t = covered + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// How the auth providers are queried
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthStrategy {
+    /// All at once, the first successful answer wins
+    #[default]
+    Race,
+    /// One by one in the configured order, the next is asked only if the previous didn't know the player
+    Ordered,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthProviders(pub Vec<AuthProvider>);
 
+impl AuthProviders {
+    /// Offline providers without a username or UUID namespace, which answer for anyone faster than real providers
+    /// when they are raced against them. Such a setup confirms every player without verifying anyone.
+    pub fn unbounded_offline(&self, strategy: AuthStrategy) -> Vec<&str> {
+        if strategy != AuthStrategy::Race || self.0.iter().all(|provider| provider.kind == AuthProviderKind::Offline) {
+            return Vec::new();
+        }
+        self.0.iter()
+            .filter(|provider| provider.kind == AuthProviderKind::Offline && provider.usernames.is_empty() && provider.uuids.is_empty())
+            .map(|provider| provider.name.as_str())
+            .collect()
+    }
+}
+
 pub fn default_authproviders() -> AuthProviders {
     AuthProviders(vec![
-        AuthProvider { name: "Mojang".to_string(), url: "https://sessionserver.mojang.com/session/minecraft/hasJoined".to_string() },
-        AuthProvider { name: "ElyBy".to_string(), url: "https://account.ely.by/api/minecraft/session/hasJoined".to_string() }
+        AuthProvider { name: "Mojang".to_string(), url: "https://sessionserver.mojang.com/session/minecraft/hasJoined".to_string(), ..Default::default() },
+        AuthProvider { name: "ElyBy".to_string(), url: "https://account.ely.by/api/minecraft/session/hasJoined".to_string(), ..Default::default() }
         ])
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_provider_racing_real_ones() {
+        let offline = |usernames: &[&str], uuids: &[&str]| AuthProvider {
+            name: "LAN".to_string(),
+            kind: AuthProviderKind::Offline,
+            usernames: usernames.iter().map(|u| u.to_string()).collect(),
+            uuids: uuids.iter().map(|u| u.to_string()).collect(),
+            ..Default::default()
+        };
+        let with = |provider: AuthProvider| AuthProviders([default_authproviders().0, vec![provider]].concat());
+        assert_eq!(with(offline(&[], &[])).unbounded_offline(AuthStrategy::Race), ["LAN"]);
+        assert!(with(offline(&[], &[])).unbounded_offline(AuthStrategy::Ordered).is_empty());
+        assert!(with(offline(&["bot_*"], &[])).unbounded_offline(AuthStrategy::Race).is_empty());
+        assert!(with(offline(&[], &["v3"])).unbounded_offline(AuthStrategy::Race).is_empty());
+        // Nothing to race against
+        assert!(AuthProviders(vec![offline(&[], &[])]).unbounded_offline(AuthStrategy::Race).is_empty());
+    }
+
+    #[test]
+    fn username_patterns() {
+        assert!(wildcard_match("*", "Steve"));
+        assert!(wildcard_match("ely_*", "Ely_Steve"));
+        assert!(wildcard_match("*_bot", "farm_bot"));
+        assert!(wildcard_match("a*b*c", "aXbYbc"));
+        assert!(!wildcard_match("ely_*", "Steve"));
+        assert!(!wildcard_match("steve", "steve2"));
+
+        let provider = AuthProvider { usernames: vec!["lan_*".to_string()], ..Default::default() };
+        assert!(provider.accepts("LAN_Alex"));
+        assert!(!provider.accepts("Alex"));
+        assert!(AuthProvider::default().accepts("Alex"));
+    }
+
+    #[test]
+    fn uuid_rules() {
+        let offline = crate::auth::offline_uuid("Steve");
+        let online = Uuid::parse_str("069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap();
+        let mojang = AuthProvider { uuids: vec!["v4".to_string()], ..Default::default() };
+        assert!(mojang.owns(&online));
+        assert!(!mojang.owns(&offline));
+
+        let lan = AuthProvider { uuids: vec!["V3".to_string(), "069a79f4-*".to_string()], ..Default::default() };
+        assert!(lan.owns(&offline));
+        assert!(lan.owns(&online));
+        assert!(!lan.owns(&Uuid::parse_str("00000000-0000-1000-8000-000000000001").unwrap()));
+
+        let exact = AuthProvider { uuids: vec![online.to_string().to_uppercase()], ..Default::default() };
+        assert!(exact.owns(&online));
+        assert!(AuthProvider::default().owns(&offline));
+    }
+}
@@ -4,6 +4,8 @@ pub const CONFIG_ENV: &str = "RUST_CONFIG";
 pub const LOGS_ENV: &str = "LOGS_FOLDER";
 pub const ASSETS_ENV: &str = "ASSETS_FOLDER";
 pub const AVATARS_ENV: &str = "AVATARS_FOLDER";
+pub const USERS_ENV: &str = "USERS_FOLDER";
+pub const IP_BANS_ENV: &str = "IP_BANS_FILE";
 
 // Instance info
 pub const SCULPTOR_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -1,9 +1,8 @@
 #![allow(clippy::module_inception)]
 use anyhow::Result;
 use axum::{
-    extract::DefaultBodyLimit, routing::{delete, get, post, put}, Router
+    routing::{delete, get, post, put}, Router
 };
-use dashmap::DashMap;
 use tracing_panic::panic_hook;
 use tracing_subscriber::{fmt::{self, time::ChronoLocal}, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 use std::{env::var, path::PathBuf, sync::{Arc, LazyLock}};
@@ -23,11 +22,16 @@ pub use metrics::*;
 
 // API
 mod api;
-use api::figura::{ws, info as api_info, profile as api_profile, auth as api_auth, assets as api_assets};
+use api::figura::{ws, Sessions, Subscriptions, info as api_info, profile as api_profile, auth as api_auth, assets as api_assets};
 
 // Auth
 mod auth;
-use auth::{UManager, check_auth};
+use auth::{purge_expired_auth, reject_banned_ips, IpBans, UManager, UserStore, check_auth};
+
+// Avatars
+mod moon;
+mod storage;
+use storage::{refresh_avatar_index, AvatarStorage, Avatars, StorageConfig};
 
 // Config
 mod state;
@@ -52,6 +56,12 @@ pub static ASSETS_VAR: LazyLock<String> = LazyLock::new(|| {
 pub static AVATARS_VAR: LazyLock<String> = LazyLock::new(|| {
     var(AVATARS_ENV).unwrap_or(String::from("data/avatars"))
 });
+pub static USERS_VAR: LazyLock<String> = LazyLock::new(|| {
+    var(USERS_ENV).unwrap_or(String::from("data/users"))
+});
+pub static IP_BANS_VAR: LazyLock<String> = LazyLock::new(|| {
+    var(IP_BANS_ENV).unwrap_or(String::from("data/ip-bans.json"))
+});
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -125,8 +135,8 @@ async fn main() -> Result<()> {
 async fn app() -> Result<bool> {
     // Config
     let config = Config::parse(CONFIG_VAR.clone().into());
+    config.warn_misconfiguration();
     let listen = config.listen.clone();
-    let limit = get_limit_as_bytes(config.limitations.max_avatar_size as usize);
 
     if config.assets_updater_enabled {
         // Force update assets if folder or hash file doesn't exists.
@@ -153,12 +163,26 @@ async fn app() -> Result<bool> {
         }
     }
 
+    // Avatars
+    let avatars = Arc::new(Avatars::new(AvatarStorage::new(&config.storage)?));
+    match avatars.refresh_index().await {
+        Ok(count) => tracing::info!("Avatar storage contains {count} avatars"),
+        Err(e) => tracing::error!("Can't access avatar storage due: {e:?}"),
+    }
+    if let StorageConfig::S3(s3) = &config.storage {
+        tokio::spawn(refresh_avatar_index(Arc::clone(&avatars), std::time::Duration::from_secs(s3.index_refresh)));
+    }
+
     // State
     let state = AppState {
         uptime: Instant::now(),
-        user_manager: Arc::new(UManager::new()),
-        session: Arc::new(DashMap::new()),
-        subscribes: Arc::new(DashMap::new()),
+        user_manager: Arc::new(UManager::with_store(UserStore::new(&*USERS_VAR))?),
+        session: Arc::new(Sessions::default()),
+        avatars,
+        subscriptions: Arc::new(Subscriptions::default()),
+        providers_health: Arc::new(Default::default()),
+        ip_bans: Arc::new(IpBans::with_file(&*IP_BANS_VAR)?),
+        minecraft: Arc::new(Default::default()),
         figura_versions: Arc::new(RwLock::new(None)),
         config: Arc::new(RwLock::new(config.clone())),
     };
@@ -170,34 +194,62 @@ async fn app() -> Result<bool> {
         Arc::clone(&state.session),
         Arc::clone(&state.config)
     ));
-    // Blacklist auto update
-    if config.mc_folder.exists() {
+    // Authentications that were never verified and expired sessions
+    tokio::spawn(purge_expired_auth(Arc::clone(&state.user_manager), Arc::clone(&state.config)));
+    // Blacklist, whitelist and operators auto update. Files are picked up even if they appear later
+    let mc_configured = !config.mc_folder.as_os_str().is_empty();
+    if config.mc_whitelist && !(mc_configured && config.mc_folder.join("whitelist.json").is_file()) {
+        tracing::warn!("Whitelist mode is enabled, but there is no whitelist.json in mcFolder. Nobody will be able to log in until it appears!");
+    }
+    if mc_configured {
+        if !config.mc_folder.is_dir() {
+            tracing::warn!("mcFolder {} doesn't exist, waiting for it", config.mc_folder.display());
+        }
         tokio::spawn(update_bans_from_minecraft(
-            state.config.read().await.mc_folder.clone(),
+            config.mc_folder.clone(),
             Arc::clone(&state.user_manager),
             Arc::clone(&state.session)
         ));
+        tokio::spawn(update_ip_bans_from_minecraft(config.mc_folder.clone(), Arc::clone(&state.ip_bans)));
+        tokio::spawn(update_whitelist_from_minecraft(
+            config.mc_folder.clone(),
+            Arc::clone(&state.user_manager),
+            Arc::clone(&state.session),
+            Arc::clone(&state.minecraft),
+            Arc::clone(&state.config)
+        ));
+        tokio::spawn(update_ops_from_minecraft(
+            config.mc_folder.clone(),
+            Arc::clone(&state.user_manager),
+            Arc::clone(&state.minecraft),
+            Arc::clone(&state.config)
+        ));
     }
 
+    // Banned addresses can't authenticate or connect
+    let ip_filter = axum::middleware::from_fn_with_state(state.clone(), reject_banned_ips);
+
     let api = Router::new()
-        .nest("//auth", api_auth::router()) // => /api//auth ¯\_(ツ)_/¯
+        .nest("//auth", api_auth::router().route_layer(ip_filter.clone())) // => /api//auth ¯\_(ツ)_/¯
         .nest("//assets", api_assets::router())
-        .nest("/auth", api_auth::router())
+        .nest("/auth", api_auth::router().route_layer(ip_filter.clone()))
         .nest("/assets", api_assets::router())
-        .nest("/v1", api::sculptor::router(limit))
+        .nest("/v1", api::sculptor::router())
         .route("/limits", get(api_info::limits))
         .route("/version", get(api_info::version))
         .route("/motd", get(api_info::motd))
         .route("/equip", post(api_profile::equip_avatar))
         .route("/{uuid}", get(api_profile::user_info))
-        .route("/{uuid}/avatar", get(api_profile::download_avatar))
-        .route("/avatar", put(api_profile::upload_avatar).layer(DefaultBodyLimit::max(limit)))
-        .route("/avatar", delete(api_profile::delete_avatar));
+        .route("/{uuid}/{id}", get(api_profile::download_avatar))
+        .route("/avatar", put(api_profile::upload_avatar))
+        .route("/avatar", delete(api_profile::delete_avatar))
+        .route("/avatar/{id}", put(api_profile::upload_avatar))
+        .route("/avatar/{id}", delete(api_profile::delete_avatar));
 
     let app = Router::new()
         .nest("/api", api)
         .route("/api/", get(check_auth))
-        .route("/ws", get(ws))
+        .route("/ws", get(ws).route_layer(ip_filter))
         .merge(metrics::metrics_router(config.metrics_enabled))
         .with_state(state) 
         .layer(TraceLayer::new_for_http()
@@ -216,7 +268,7 @@ async fn app() -> Result<bool> {
     let listener = tokio::net::TcpListener::bind(listen).await?;
     tracing::info!("Listening on {}", listener.local_addr()?);
 
-    axum::serve(listener, app)
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
     
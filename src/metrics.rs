@@ -2,7 +2,7 @@
 use std::{sync::LazyLock, time::Instant};
 
 use axum::{body::Body, extract::State, http::{Request, Response}, middleware::Next, routing::get, Router};
-use prometheus::{proto::{Metric, MetricType}, register_histogram_vec, register_int_counter};
+use prometheus::{proto::{Metric, MetricType}, register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge};
 use reqwest::StatusCode;
 
 use crate::state::AppState;
@@ -20,7 +20,7 @@ async fn metrics(State(state): State<AppState>) -> String {
     // Add new custom metrics
     let players = {
         let mut gauge = prometheus::proto::Gauge::default();
-        gauge.set_value(state.session.len() as f64);
+        gauge.set_value(state.session.count() as f64);
         
         let mut metric = prometheus::proto::Metric::default();
         metric.set_gauge(gauge);
@@ -80,4 +80,44 @@ pub static PINGS: LazyLock<prometheus::HistogramVec> = LazyLock::new(|| {
 
 pub static PINGS_ERROR: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
     register_int_counter!("sculptor_pings_error", "Number of ping decoding errors").unwrap()
-});
\ No newline at end of file
+});
+
+pub static AUTH_PENDING: LazyLock<prometheus::IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("sculptor_auth_pending", "Number of authentications waiting for verification").unwrap()
+});
+
+pub static AUTH_PENDING_EXPIRED: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    register_int_counter!("sculptor_auth_pending_expired", "Number of authentications never verified in time").unwrap()
+});
+
+pub static AUTH_UNKNOWN_SERVER_ID: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    register_int_counter!("sculptor_auth_unknown_server_id", "Number of verifications with unknown or expired serverId").unwrap()
+});
+
+pub static AUTH_PROVIDER_REQUESTS: LazyLock<prometheus::IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("sculptor_auth_provider_requests", "Number of requests to auth providers", &["provider", "result"]).unwrap()
+});
+
+pub static AUTH_PROVIDER_LATENCY: LazyLock<prometheus::HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!("sculptor_auth_provider_latency", "Auth provider response time", &["provider"]).unwrap()
+});
+
+pub static IP_BANS_REJECTED: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    register_int_counter!("sculptor_ip_bans_rejected", "Number of requests rejected due to IP bans").unwrap()
+});
+
+pub static PINGS_REJECTED: LazyLock<prometheus::IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!("sculptor_pings_rejected", "Number of pings dropped due to limits", &["reason"]).unwrap()
+});
+
+pub static PINGS_DISCONNECTS: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    register_int_counter!("sculptor_pings_disconnects", "Number of clients disconnected for exceeding ping limits").unwrap()
+});
+
+pub static PINGS_LAGGED: LazyLock<prometheus::IntCounter> = LazyLock::new(|| {
+    register_int_counter!("sculptor_pings_lagged", "Number of pings skipped by subscribers that couldn't keep up").unwrap()
+});
+
+pub static SESSION_LAGGED_PINGS: LazyLock<prometheus::Histogram> = LazyLock::new(|| {
+    register_histogram!("sculptor_session_lagged_pings", "Pings skipped during a session", vec![0.0, 1.0, 10.0, 100.0, 1000.0]).unwrap()
+});
@@ -0,0 +1,101 @@
+use serde::Serialize;
+
+use super::{Moon, Tag};
+
+/// Summary of an avatar for moderators
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AvatarMeta {
+    pub name: Option<String>,
+    pub authors: Vec<String>,
+    /// Figura version the avatar was made for
+    pub version: Option<String>,
+    pub script_count: usize,
+    pub scripts: Vec<String>,
+    pub texture_count: usize,
+    pub uncompressed_size: u64,
+}
+
+impl Moon {
+    pub fn meta(&self) -> AvatarMeta {
+        let metadata = self.root.get("metadata");
+        let string = |key: &str| match metadata.and_then(|m| m.get(key)) {
+            Some(Tag::String(value)) if !value.is_empty() => Some(value.clone()),
+            _ => None,
+        };
+
+        // Figura joins the authors list with newlines
+        let authors = string("authors")
+            .map(|authors| authors.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+
+        let scripts: Vec<String> = match self.root.get("scripts") {
+            Some(Tag::Compound(scripts)) => scripts.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+
+        let texture_count = match self.root.get("textures").and_then(|t| t.get("src")) {
+            Some(Tag::Compound(textures)) => textures.len(),
+            _ => 0,
+        };
+
+        AvatarMeta {
+            name: string("name"),
+            authors,
+            version: string("ver"),
+            script_count: scripts.len(),
+            scripts,
+            texture_count,
+            uncompressed_size: self.uncompressed_size,
+        }
+    }
+}
+
+impl Tag {
+    /// Child of a compound tag
+    pub fn get(&self, key: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(compound) => compound.get(key),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::IndexMap;
+
+    use super::*;
+
+    fn compound<const N: usize>(entries: [(&str, Tag); N]) -> Tag {
+        Tag::Compound(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    #[test]
+    fn extracts_meta() {
+        let root = compound([
+            ("metadata", compound([
+                ("name", Tag::String("Cool avatar".to_string())),
+                ("authors", Tag::String("Alice\nBob".to_string())),
+                ("ver", Tag::String("0.1.5".to_string())),
+            ])),
+            ("scripts", compound([("script", Tag::ByteArray(vec![])), ("libs.util", Tag::ByteArray(vec![]))])),
+            ("textures", compound([("src", compound([("skin", Tag::ByteArray(vec![]))])), ("data", Tag::List(vec![]))])),
+        ]);
+        let Tag::Compound(root) = root else { unreachable!() };
+        let meta = Moon { root, uncompressed_size: 42 }.meta();
+
+        assert_eq!(meta, AvatarMeta {
+            name: Some("Cool avatar".to_string()),
+            authors: vec!["Alice".to_string(), "Bob".to_string()],
+            version: Some("0.1.5".to_string()),
+            script_count: 2,
+            scripts: vec!["script".to_string(), "libs.util".to_string()],
+            texture_count: 1,
+            uncompressed_size: 42,
+        });
+
+        let empty = Moon { root: IndexMap::new(), uncompressed_size: 0 }.meta();
+        assert_eq!((empty.name, empty.script_count, empty.texture_count), (None, 0, 0));
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+use indexmap::IndexMap;
+use thiserror::Error;
+
+mod meta;
+mod nbt;
+
+pub use meta::*;
+pub use nbt::*;
+
+/// Largest uncompressed avatar accepted from a user with the given `maxAvatarSize` (in bytes),
+/// `ratio` is `limitations.uncompressedRatio`
+pub fn uncompressed_limit(max_avatar_size: u64, ratio: u64) -> u64 {
+    max_avatar_size.saturating_mul(ratio)
+}
+
+#[derive(Error, Debug)]
+pub enum MoonError {
+    #[error("avatar is not gzip-compressed")]
+    NotGzip,
+    #[error("broken gzip stream: {0}")]
+    Gzip(#[from] std::io::Error),
+    #[error("uncompressed avatar is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("invalid NBT: {0}")]
+    Nbt(#[from] NbtError),
+}
+
+/// Parsed `.moon` avatar
+#[derive(Debug)]
+pub struct Moon {
+    pub root: IndexMap<String, Tag>,
+    pub uncompressed_size: u64,
+}
+
+/// Decompresses and parses a `.moon` avatar (gzip-compressed NBT compound).
+/// Decompression stops as soon as `max_uncompressed` bytes are exceeded, see [`uncompressed_limit`].
+pub fn decode(data: &[u8], max_uncompressed: u64) -> Result<Moon, MoonError> {
+    if !data.starts_with(&[0x1f, 0x8b]) {
+        return Err(MoonError::NotGzip);
+    }
+    let mut uncompressed = Vec::new();
+    GzDecoder::new(data).take(max_uncompressed.saturating_add(1)).read_to_end(&mut uncompressed)?;
+    if uncompressed.len() as u64 > max_uncompressed {
+        return Err(MoonError::TooLarge(max_uncompressed));
+    }
+    let (_, root) = read_root(&uncompressed)?;
+    Ok(Moon { root, uncompressed_size: uncompressed.len() as u64 })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn decode_moon() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[10, 0, 0, 1, 0, 1, b'a', 7, 0]).unwrap();
+        let moon = encoder.finish().unwrap();
+
+        let decoded = decode(&moon, 9).unwrap();
+        assert_eq!(decoded.root["a"], Tag::Byte(7));
+        assert_eq!(decoded.uncompressed_size, 9);
+        assert!(matches!(decode(b"not gzip", 9), Err(MoonError::NotGzip)));
+        assert!(matches!(decode(&moon[..moon.len() - 4], 9), Err(MoonError::Gzip(_))));
+    }
+
+    #[test]
+    fn rejects_gzip_bomb() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&vec![0; 1024 * 1024]).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        let limit = uncompressed_limit(bomb.len() as u64, 16);
+        assert!(limit < 1024 * 1024);
+        assert!(matches!(decode(&bomb, limit), Err(MoonError::TooLarge(max)) if max == limit));
+    }
+}
@@ -0,0 +1,222 @@
+use indexmap::IndexMap;
+use thiserror::Error;
+
+/// Same limit as in Minecraft's NbtAccounter
+const MAX_DEPTH: usize = 512;
+
+/// Most tags one document may contain. Every tag takes dozens of bytes in memory,
+/// while a list element can be encoded in a single byte.
+pub const MAX_TAGS: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(IndexMap<String, Tag>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum NbtError {
+    #[error("unexpected end of data")]
+    UnexpectedEof,
+    #[error("unknown tag type {0}")]
+    UnknownTag(u8),
+    #[error("negative length {0}")]
+    NegativeLength(i32),
+    #[error("list of TAG_End with {0} elements")]
+    EndList(i32),
+    #[error("nesting is deeper than {MAX_DEPTH}")]
+    TooDeep,
+    #[error("root tag must be a compound, found type {0}")]
+    RootNotCompound(u8),
+    #[error("more than {MAX_TAGS} tags")]
+    TooManyTags,
+}
+
+/// Reads a named root compound from uncompressed NBT data.
+pub fn read_root(data: &[u8]) -> Result<(String, IndexMap<String, Tag>), NbtError> {
+    let mut reader = Reader { data, depth: 0, tags: 0 };
+    match reader.u8()? {
+        10 => {
+            let name = reader.string()?;
+            match reader.payload(10)? {
+                Tag::Compound(root) => Ok((name, root)),
+                _ => unreachable!(),
+            }
+        },
+        kind => Err(NbtError::RootNotCompound(kind)),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    depth: usize,
+    /// Tags read so far, limited by [`MAX_TAGS`]
+    tags: usize,
+}
+
+/// Smallest possible encoding of a payload of the type, used to reject forged lengths early
+fn min_size(kind: u8) -> Result<usize, NbtError> {
+    Ok(match kind {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 | 5 => 4,
+        4 | 6 => 8,
+        7 | 11 | 12 => 4, // Length
+        8 => 2, // Length
+        9 => 5, // Element type and length
+        10 => 1, // TAG_End
+        kind => return Err(NbtError::UnknownTag(kind)),
+    })
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], NbtError> {
+        if self.data.len() < len {
+            return Err(NbtError::UnexpectedEof);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], NbtError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, NbtError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    /// Length prefix of arrays and lists. `size` is the minimal size of one element.
+    fn len(&mut self, size: usize) -> Result<usize, NbtError> {
+        let len = i32::from_be_bytes(self.array()?);
+        if len < 0 {
+            return Err(NbtError::NegativeLength(len));
+        }
+        // Checking before allocation, so a forged length can't eat all memory
+        if (len as usize).saturating_mul(size) > self.data.len() {
+            return Err(NbtError::UnexpectedEof);
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, NbtError> {
+        let len = u16::from_be_bytes(self.array()?) as usize;
+        // Java writes "modified UTF-8", which differs only for NUL and supplementary characters
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn payload(&mut self, kind: u8) -> Result<Tag, NbtError> {
+        self.tags += 1;
+        if self.tags > MAX_TAGS {
+            return Err(NbtError::TooManyTags);
+        }
+        Ok(match kind {
+            1 => Tag::Byte(i8::from_be_bytes(self.array()?)),
+            2 => Tag::Short(i16::from_be_bytes(self.array()?)),
+            3 => Tag::Int(i32::from_be_bytes(self.array()?)),
+            4 => Tag::Long(i64::from_be_bytes(self.array()?)),
+            5 => Tag::Float(f32::from_be_bytes(self.array()?)),
+            6 => Tag::Double(f64::from_be_bytes(self.array()?)),
+            7 => {
+                let len = self.len(1)?;
+                Tag::ByteArray(self.take(len)?.to_vec())
+            },
+            8 => Tag::String(self.string()?),
+            9 => {
+                let element = self.u8()?;
+                let len = self.len(min_size(element)?)?;
+                if element == 0 && len > 0 {
+                    return Err(NbtError::EndList(len as i32));
+                }
+                self.nested(|reader| (0..len).map(|_| reader.payload(element)).collect::<Result<_, _>>().map(Tag::List))?
+            },
+            10 => self.nested(|reader| {
+                let mut compound = IndexMap::new();
+                loop {
+                    let kind = reader.u8()?;
+                    if kind == 0 {
+                        break Ok(Tag::Compound(compound));
+                    }
+                    let name = reader.string()?;
+                    compound.insert(name, reader.payload(kind)?);
+                }
+            })?,
+            11 => {
+                let len = self.len(4)?;
+                Tag::IntArray((0..len).map(|_| self.array().map(i32::from_be_bytes)).collect::<Result<_, _>>()?)
+            },
+            12 => {
+                let len = self.len(8)?;
+                Tag::LongArray((0..len).map(|_| self.array().map(i64::from_be_bytes)).collect::<Result<_, _>>()?)
+            },
+            kind => return Err(NbtError::UnknownTag(kind)),
+        })
+    }
+
+    fn nested(&mut self, read: impl FnOnce(&mut Self) -> Result<Tag, NbtError>) -> Result<Tag, NbtError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(NbtError::TooDeep);
+        }
+        let tag = read(self);
+        self.depth -= 1;
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_nested_compound() {
+        let data = [
+            10, 0, 0, // root ""
+            8, 0, 4, b'n', b'a', b'm', b'e', 0, 2, b'h', b'i', // name: "hi"
+            9, 0, 4, b'l', b'i', b's', b't', 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, // list: [1, 2]
+            7, 0, 1, b'b', 0, 0, 0, 3, 1, 2, 3, // b: [1, 2, 3]
+            0,
+        ];
+        let (name, root) = read_root(&data).unwrap();
+        assert_eq!(name, "");
+        assert_eq!(root["name"], Tag::String("hi".to_string()));
+        assert_eq!(root["list"], Tag::List(vec![Tag::Int(1), Tag::Int(2)]));
+        assert_eq!(root["b"], Tag::ByteArray(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(read_root(&[8, 0, 0]), Err(NbtError::RootNotCompound(8)));
+        assert_eq!(read_root(&[10, 0, 0, 1, 0, 1, b'a']), Err(NbtError::UnexpectedEof));
+        assert_eq!(read_root(&[10, 0, 0, 7, 0, 0, 0x7f, 0xff, 0xff, 0xff, 0]), Err(NbtError::UnexpectedEof));
+        assert_eq!(read_root(&[10, 0, 0, 9, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0]), Err(NbtError::EndList(5)));
+        assert_eq!(read_root(&[10, 0, 0, 13, 0, 0, 0]), Err(NbtError::UnknownTag(13)));
+        // 2 ints can't fit into 7 bytes
+        assert_eq!(read_root(&[10, 0, 0, 9, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]), Err(NbtError::UnexpectedEof));
+        assert_eq!(read_root(&[10, 0, 0, 9, 0, 0, 13, 0, 0, 0, 1, 0]), Err(NbtError::UnknownTag(13)));
+
+        let mut deep = vec![10, 0, 0];
+        for _ in 0..=MAX_DEPTH {
+            deep.extend([10, 0, 0]);
+        }
+        assert_eq!(read_root(&deep), Err(NbtError::TooDeep));
+
+        let mut bytes = vec![10, 0, 0, 9, 0, 0, 1];
+        bytes.extend((MAX_TAGS as i32).to_be_bytes());
+        bytes.resize(bytes.len() + MAX_TAGS, 0);
+        bytes.push(0);
+        assert_eq!(read_root(&bytes), Err(NbtError::TooManyTags));
+    }
+}
@@ -1,10 +1,12 @@
-use std::{collections::HashMap, io::Read, path::PathBuf};
+use std::{collections::HashMap, io::Read, path::PathBuf, time::Duration};
 
-use serde::Deserialize;
-use tracing::{debug, warn};
+use ring::digest::{self, digest};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
-use crate::auth::{default_authproviders, AuthProviders, Userinfo};
+use crate::{auth::{default_authproviders, AuthProviders, AuthStrategy, CircuitBreakerConfig, IpRange, Userinfo}, storage::StorageConfig};
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -12,16 +14,56 @@ pub struct Config {
     pub listen: String,
     #[serde(default)]
     pub metrics_enabled: bool,
+    /// Full admin token, kept for old configs. Prefer `adminTokens`
     pub token: Option<String>,
+    #[serde(default)]
+    pub admin_tokens: Vec<AdminToken>,
     pub assets_updater_enabled: bool,
     pub motd: CMotd,
     #[serde(default = "default_authproviders")]
     pub auth_providers: AuthProviders,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
     pub limitations: Limitations,
     #[serde(default)]
     pub mc_folder: PathBuf,
+    /// Only players from whitelist.json in mcFolder can authenticate
+    #[serde(default)]
+    pub mc_whitelist: bool,
+    /// Rank given to players from ops.json in mcFolder
+    #[serde(default)]
+    pub mc_ops_rank: Option<String>,
     #[serde(default)]
     pub advanced_users: HashMap<Uuid, AdvancedUsers>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+}
+
+/// Named token for the admin API
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminToken {
+    /// Shown in logs
+    pub name: String,
+    pub role: AdminRole,
+    /// Hex SHA-256 of the token, the token itself isn't stored
+    pub sha256: String,
+}
+
+/// What an admin token is allowed to do, every role includes the previous ones
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminRole {
+    /// Lists and metadata
+    ReadOnly,
+    /// Bans, kicks and avatar removal
+    Moderator,
+    /// Everything, including creating users, uploading avatars and sending raw messages
+    Admin,
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
@@ -37,11 +79,208 @@ pub struct CMotd {
     pub draw_indent: bool,
 }
 
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthConfig {
+    /// Seconds a client has to finish authentication after receiving a serverId
+    #[serde(default = "default_pending_ttl")]
+    pub pending_ttl: u64,
+    /// Seconds before a session token expires and the client has to authenticate again
+    #[serde(default = "default_token_lifetime")]
+    pub token_lifetime: u64,
+    #[serde(default)]
+    pub session_policy: SessionPolicy,
+    #[serde(default)]
+    pub strategy: AuthStrategy,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            pending_ttl: default_pending_ttl(),
+            token_lifetime: default_token_lifetime(),
+            session_policy: SessionPolicy::default(),
+            strategy: AuthStrategy::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+        }
+    }
+}
+
+/// What to do when a player logs in while already connected
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SessionPolicy {
+    /// Refuse the new login
+    Reject,
+    /// Disconnect the old session
+    #[default]
+    Replace,
+    /// Keep both sessions
+    AllowMultiple,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkConfig {
+    /// Proxies allowed to pass the client address in X-Forwarded-For or X-Real-IP
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpRange>,
+    /// Addresses and ranges that can't authenticate or connect
+    #[serde(default)]
+    pub banned_ips: Vec<IpRange>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebSocketConfig {
+    /// Seconds between pings sent to the client, 0 disables them
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds the client has to answer a ping, also the longest a single send may take
+    #[serde(default = "default_pong_timeout", deserialize_with = "deserialize_pong_timeout")]
+    pub pong_timeout: u64,
+    /// Seconds without any data from the client before it's disconnected, 0 disables it. Pings and pongs don't count
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Players a single connection can subscribe to
+    #[serde(default = "default_max_subscriptions")]
+    pub max_subscriptions: usize,
+    /// Pings of subscribed players queued for a single connection, `max_subscriptions` if not set.
+    /// Pings that don't fit are skipped, events and moderation messages are never dropped.
+    #[serde(default)]
+    pub session_channel_capacity: Option<usize>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: default_heartbeat_interval(),
+            pong_timeout: default_pong_timeout(),
+            idle_timeout: default_idle_timeout(),
+            max_subscriptions: default_max_subscriptions(),
+            session_channel_capacity: None,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_interval != 0).then(|| Duration::from_secs(self.heartbeat_interval))
+    }
+
+    pub fn pong(&self) -> Duration {
+        Duration::from_secs(self.pong_timeout)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        (self.idle_timeout != 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+
+    /// Room for a ping from every subscribed player by default. The queue allocates as it fills, so a large one costs nothing while idle
+    pub fn ping_queue(&self) -> usize {
+        self.session_channel_capacity.unwrap_or(self.max_subscriptions).max(1)
+    }
+}
+
+fn default_heartbeat_interval() -> u64 {
+    30
+}
+
+fn default_pong_timeout() -> u64 {
+    15
+}
+
+/// Zero would time out every send, so the connection couldn't even be closed
+fn deserialize_pong_timeout<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("pongTimeout must be at least 1 second")),
+        secs => Ok(secs),
+    }
+}
+
+fn default_idle_timeout() -> u64 {
+    120
+}
+
+fn default_max_subscriptions() -> usize {
+    1024
+}
+
+fn default_pending_ttl() -> u64 {
+    60
+}
+
+fn default_token_lifetime() -> u64 {
+    24 * 60 * 60
+}
+
 #[derive(Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Limitations {
     pub max_avatar_size: u64,
     pub max_avatars: u64,
+    /// Bytes of pings a client can send per second
+    #[serde(default = "default_ping_size")]
+    pub ping_size: u64,
+    /// Pings a client can send per second
+    #[serde(default = "default_ping_rate")]
+    pub ping_rate: u64,
+    /// Dropped pings after which the client is disconnected. If not set, pings are only dropped
+    #[serde(default)]
+    pub max_ping_violations: Option<u32>,
+    /// How many times an avatar may grow when decompressed, anything beyond that is treated as a gzip bomb
+    #[serde(default = "default_uncompressed_ratio", deserialize_with = "deserialize_uncompressed_ratio")]
+    pub uncompressed_ratio: u64,
+    /// Overrides for players with `Userinfo::rank`
+    #[serde(default)]
+    pub ranks: HashMap<String, Quota>,
+}
+
+fn default_ping_size() -> u64 {
+    1024
+}
+
+fn default_ping_rate() -> u64 {
+    32
+}
+
+/// Textures are already compressed, scripts and models shrink a few times at most
+fn default_uncompressed_ratio() -> u64 {
+    16
+}
+
+fn deserialize_uncompressed_ratio<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("uncompressedRatio must be at least 1")),
+        ratio => Ok(ratio),
+    }
+}
+
+/// Partial override of the global limitations
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    /// KB
+    pub max_avatar_size: Option<u64>,
+    pub max_avatars: Option<u64>,
+    pub ping_size: Option<u64>,
+    pub ping_rate: Option<u64>,
+    pub max_ping_violations: Option<u32>,
+}
+
+/// Limitations applied to a specific user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserLimits {
+    /// Bytes
+    pub max_avatar_size: u64,
+    /// Bytes, decompressed avatars can't be larger
+    pub max_uncompressed_size: u64,
+    pub max_avatars: u64,
+    pub ping_size: u64,
+    pub ping_rate: u64,
+    pub max_ping_violations: Option<u32>,
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
@@ -55,6 +294,8 @@ pub struct AdvancedUsers {
     pub special: [u8;6],
     #[serde(default)]
     pub pride: [u8;25],
+    #[serde(flatten)]
+    pub quota: Quota,
 }
 
 #[derive(Deserialize, Clone, Debug, PartialEq)]
@@ -64,6 +305,35 @@ pub struct BannedPlayer {
     pub name: String,
 }
 
+/// Entry of whitelist.json or ops.json
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ListedPlayer {
+    pub uuid: Uuid,
+    pub name: String,
+}
+
+/// Entry of banned-ips.json
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BannedIp {
+    pub ip: IpRange,
+    /// "forever" or a date like "2024-05-01 12:00:00 +0000"
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+impl BannedIp {
+    /// When the ban ends, `None` if never. Unreadable dates keep the ban.
+    pub fn expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        let expires = self.expires.as_deref().filter(|expires| !expires.eq_ignore_ascii_case("forever"))?;
+        chrono::DateTime::parse_from_str(expires, "%Y-%m-%d %H:%M:%S %z")
+            .inspect_err(|e| warn!("Can't parse expiration date {expires:?} of the {} ban due: {e}", self.ip))
+            .ok()
+            .map(|expires| expires.to_utc())
+    }
+}
+
 impl From<BannedPlayer> for Userinfo {
     fn from(val: BannedPlayer) -> Self {
         Userinfo {
@@ -84,22 +354,129 @@ impl Config {
         toml::from_str(&data).unwrap_or_else(|err| {tracing::error!("{err:#?}"); panic!("Panic occured! See log messages!")})
     }
 
-    pub fn verify_token(&self, suspicious: &str) -> crate::ApiResult<()> {
+    /// Logs settings which are accepted, but most likely not what the owner wants
+    pub fn warn_misconfiguration(&self) {
+        for name in self.auth_providers.unbounded_offline(self.auth.strategy) {
+            error!(
+                "Offline auth provider {name} is raced against real providers without usernames or uuids limits. \
+                It answers first, so ANYONE can log in as ANY player! Limit it or set auth.strategy = \"ordered\""
+            );
+        }
+    }
+
+    /// Per-UUID overrides from `advancedUsers` win over rank ones, which win over the global limitations.
+    pub fn limits_for(&self, uuid: &Uuid, rank: &str) -> UserLimits {
+        let quotas = [
+            self.advanced_users.get(uuid).map(|user| &user.quota),
+            self.limitations.ranks.get(rank),
+        ];
+        let max_avatar_size = quotas.iter().flatten().find_map(|quota| quota.max_avatar_size).unwrap_or(self.limitations.max_avatar_size);
+        let max_avatars = quotas.iter().flatten().find_map(|quota| quota.max_avatars).unwrap_or(self.limitations.max_avatars);
+        let ping_size = quotas.iter().flatten().find_map(|quota| quota.ping_size).unwrap_or(self.limitations.ping_size);
+        let ping_rate = quotas.iter().flatten().find_map(|quota| quota.ping_rate).unwrap_or(self.limitations.ping_rate);
+        let max_ping_violations = quotas.iter().flatten().find_map(|quota| quota.max_ping_violations).or(self.limitations.max_ping_violations);
+        let max_uncompressed_size = crate::moon::uncompressed_limit(max_avatar_size * 1024, self.limitations.uncompressed_ratio);
+        UserLimits { max_avatar_size: max_avatar_size * 1024, max_uncompressed_size, max_avatars, ping_size, ping_rate, max_ping_violations }
+    }
+
+    /// Checks that the token exists and has at least the `required` role. Returns the token name for logs.
+    pub fn authorize(&self, suspicious: &str, required: AdminRole) -> crate::ApiResult<String> {
         use crate::ApiError;
-        match &self.token {
-            Some(token) => {
-                if token == suspicious {
-                    debug!("Admin token passed!");
-                    Ok(())
-                } else {
-                    warn!("Unknown tryed to use admin functions, but use wrong token!");
-                    Err(ApiError::Unauthorized)
-                }
+        if self.token.is_none() && self.admin_tokens.is_empty() {
+            warn!("Unknown tryed to use admin functions, but token is not defined!");
+            return Err(ApiError::BadRequest);
+        }
+
+        let hash = faster_hex::hex_string(digest(&digest::SHA256, suspicious.as_bytes()).as_ref());
+        let found = self.admin_tokens.iter()
+            .find(|token| bool::from(token.sha256.to_ascii_lowercase().as_bytes().ct_eq(hash.as_bytes())))
+            .map(|token| (token.name.clone(), token.role))
+            .or_else(|| self.token.as_ref()
+                .filter(|token| bool::from(token.as_bytes().ct_eq(suspicious.as_bytes())))
+                .map(|_| (String::from("token"), AdminRole::Admin))
+            );
+
+        match found {
+            Some((name, role)) if role >= required => {
+                debug!("Admin token {name} passed!");
+                Ok(name)
+            },
+            Some((name, role)) => {
+                warn!("Admin token {name} ({role:?}) tryed to use {required:?} functions!");
+                Err(ApiError::Forbidden)
             },
             None => {
-                warn!("Unknown tryed to use admin functions, but token is not defined!");
-                Err(ApiError::BadRequest)
+                warn!("Unknown tryed to use admin functions, but use wrong token!");
+                Err(ApiError::Unauthorized)
             },
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::ApiError;
+
+    use super::*;
+
+    #[test]
+    fn limits_precedence() {
+        let mut config: Config = toml::from_str(include_str!("../../Config.example.toml")).unwrap();
+        config.limitations = toml::from_str(r#"
+            maxAvatarSize = 100
+            maxAvatars = 10
+            maxPingViolations = 5
+            uncompressedRatio = 4
+            [ranks.supporter]
+            maxAvatarSize = 500
+            pingRate = 64
+        "#).unwrap();
+        let supporter = Uuid::from_u128(1);
+        config.advanced_users.insert(supporter, toml::from_str("maxAvatars = 20\npingSize = 4096").unwrap());
+
+        let limits = |max_avatar_size: u64, max_avatars, ping_size, ping_rate| {
+            UserLimits { max_avatar_size: max_avatar_size * 1024, max_uncompressed_size: max_avatar_size * 1024 * 4, max_avatars, ping_size, ping_rate, max_ping_violations: Some(5) }
+        };
+        assert_eq!(config.limits_for(&Uuid::from_u128(2), "default"), limits(100, 10, 1024, 32));
+        assert_eq!(config.limits_for(&Uuid::from_u128(2), "supporter"), limits(500, 10, 1024, 64));
+        assert_eq!(config.limits_for(&supporter, "supporter"), limits(500, 20, 4096, 64));
+    }
+
+    #[test]
+    fn uncompressed_ratio_defaults() {
+        let limitations: Limitations = toml::from_str("maxAvatarSize = 100\nmaxAvatars = 10").unwrap();
+        assert_eq!(limitations.uncompressed_ratio, 16);
+        let err = toml::from_str::<Limitations>("maxAvatarSize = 100\nmaxAvatars = 10\nuncompressedRatio = 0").unwrap_err();
+        assert!(err.to_string().contains("uncompressedRatio must be at least 1"));
+    }
+
+    #[test]
+    fn pong_timeout_is_required() {
+        let config: WebSocketConfig = toml::from_str("pongTimeout = 5").unwrap();
+        assert_eq!(config.pong(), Duration::from_secs(5));
+        let err = toml::from_str::<WebSocketConfig>("pongTimeout = 0").unwrap_err();
+        assert!(err.to_string().contains("pongTimeout must be at least 1 second"));
+    }
+
+    #[test]
+    fn admin_token_roles() {
+        let mut config: Config = toml::from_str(include_str!("../../Config.example.toml")).unwrap();
+        config.token = None;
+        assert!(matches!(config.authorize("anything", AdminRole::ReadOnly), Err(ApiError::BadRequest)));
+
+        config.admin_tokens.push(AdminToken {
+            name: "panel".to_string(),
+            role: AdminRole::Moderator,
+            // sha256("secret")
+            sha256: "2BB80D537B1DA3E38BD30361AA855686BDE0EACD7162FEF6A25FE97BF527A25B".to_string(),
+        });
+        assert_eq!(config.authorize("secret", AdminRole::ReadOnly).unwrap(), "panel");
+        assert_eq!(config.authorize("secret", AdminRole::Moderator).unwrap(), "panel");
+        assert!(matches!(config.authorize("secret", AdminRole::Admin), Err(ApiError::Forbidden)));
+        assert!(matches!(config.authorize("wrong", AdminRole::ReadOnly), Err(ApiError::Unauthorized)));
+        // The hash isn't the token
+        assert!(matches!(config.authorize(&config.admin_tokens[0].sha256.clone(), AdminRole::ReadOnly), Err(ApiError::Unauthorized)));
+
+        config.token = Some("legacy".to_string());
+        assert_eq!(config.authorize("legacy", AdminRole::Admin).unwrap(), "token");
+    }
+}
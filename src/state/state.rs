@@ -1,10 +1,9 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::{Arc, RwLock as StdRwLock}};
 
-use dashmap::DashMap;
 use tokio::{sync::*, time::Instant};
 use uuid::Uuid;
 
-use crate::{api::figura::SessionMessage, auth::UManager, FiguraVersions};
+use crate::{api::figura::{Sessions, Subscriptions}, auth::{IpBans, ProvidersHealth, UManager}, storage::Avatars, FiguraVersions};
 
 #[derive(Debug, Clone)]
 pub struct AppState {
@@ -13,11 +12,62 @@ pub struct AppState {
     /// User manager
     pub user_manager: Arc<UManager>,
     /// Send into WebSocket
-    pub session: Arc<DashMap<Uuid, mpsc::Sender<SessionMessage>>>,
+    pub session: Arc<Sessions>,
+    /// Avatar files
+    pub avatars: Arc<Avatars>,
     /// Send messages for subscribers
-    pub subscribes: Arc<DashMap<Uuid, broadcast::Sender<Vec<u8>>>>,
+    pub subscriptions: Arc<Subscriptions>,
+    /// State of the auth providers
+    pub providers_health: Arc<ProvidersHealth>,
+    /// Banned IP ranges
+    pub ip_bans: Arc<IpBans>,
+    /// Whitelist and operators of the Minecraft server
+    pub minecraft: Arc<MinecraftLists>,
     /// Current configuration
     pub config: Arc<RwLock<super::Config>>,
     /// Caching Figura Versions
     pub figura_versions: Arc<RwLock<Option<FiguraVersions>>>,
-}
\ No newline at end of file
+}
+/// Players from the lists of the Minecraft server
+#[derive(Debug, Default)]
+pub struct MinecraftLists {
+    whitelist: StdRwLock<HashSet<Uuid>>,
+    ops: StdRwLock<HashSet<Uuid>>,
+}
+
+impl MinecraftLists {
+    pub fn set_whitelist(&self, whitelist: HashSet<Uuid>) {
+        *self.whitelist.write().unwrap() = whitelist;
+    }
+    pub fn is_whitelisted(&self, uuid: &Uuid) -> bool {
+        self.whitelist.read().unwrap().contains(uuid)
+    }
+    /// Replaces operators, returns the previous ones
+    pub fn set_ops(&self, ops: HashSet<Uuid>) -> HashSet<Uuid> {
+        std::mem::replace(&mut *self.ops.write().unwrap(), ops)
+    }
+    pub fn is_op(&self, uuid: &Uuid) -> bool {
+        self.ops.read().unwrap().contains(uuid)
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State with the example configuration and avatars in a temporary folder
+    pub fn for_tests() -> Self {
+        let config = toml::from_str(include_str!("../../Config.example.toml")).unwrap();
+        let folder = std::env::temp_dir().join(format!("sculptor-state-{}", Uuid::from_u128(rand::random())));
+        Self {
+            uptime: Instant::now(),
+            user_manager: Arc::new(UManager::new()),
+            session: Arc::new(Sessions::default()),
+            avatars: Arc::new(Avatars::new(crate::storage::AvatarStorage::Fs(crate::storage::FsStore::new(folder)))),
+            subscriptions: Arc::new(Subscriptions::default()),
+            providers_health: Arc::new(ProvidersHealth::default()),
+            ip_bans: Arc::new(IpBans::default()),
+            minecraft: Arc::new(MinecraftLists::default()),
+            config: Arc::new(RwLock::new(config)),
+            figura_versions: Arc::new(RwLock::new(None)),
+        }
+    }
+}
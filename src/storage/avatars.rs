@@ -0,0 +1,286 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use axum::body::Bytes;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures_util::{stream, StreamExt};
+use uuid::Uuid;
+
+use crate::utils::calculate_avatar_hash;
+
+use super::{AvatarKey, AvatarStorage, AvatarStore, AvatarStream, StoredAvatar};
+
+/// How many avatars are hashed at once while refreshing the index
+const INDEX_CONCURRENCY: usize = 16;
+
+/// Avatar storage with an in-memory index of hashes.
+/// Profile requests are served from the index without touching the backend.
+#[derive(Debug)]
+pub struct Avatars {
+    store: AvatarStorage,
+    /// <Owner, <Slot id, Hash>>
+    hashes: DashMap<Uuid, BTreeMap<String, String>>,
+    /// Bumped by every upload and removal
+    generation: AtomicU64,
+    /// <Owner, Generation of the last change>, lets the refresh skip owners changed after it has started
+    modified: DashMap<Uuid, u64>,
+    /// <Owner, <Slot id, Uploads in progress>>, slots taken by uploads that aren't stored yet
+    reserved: DashMap<Uuid, HashMap<String, usize>>,
+    /// <Avatar, (Version, Hash)> seen by the last refresh, only changed avatars are hashed again
+    versions: DashMap<AvatarKey, (String, String)>,
+}
+
+impl Avatars {
+    pub fn new(store: AvatarStorage) -> Self {
+        Self { store, hashes: DashMap::new(), generation: AtomicU64::new(0), modified: DashMap::new(), reserved: DashMap::new(), versions: DashMap::new() }
+    }
+
+    /// Rebuilds the hash index from the backend. Returns the number of indexed avatars.
+    /// Avatars listed with the same version as in the previous refresh aren't hashed again.
+    /// Owners uploading or removing avatars meanwhile keep their index entries, the listing may be already outdated for them.
+    pub async fn refresh_index(&self) -> anyhow::Result<usize> {
+        let start = Instant::now();
+        let started = self.generation.load(Ordering::SeqCst);
+        let stored = self.store.list().await?;
+
+        let listed: HashSet<AvatarKey> = stored.iter().map(|avatar| avatar.key.clone()).collect();
+        let mut actual: HashMap<Uuid, BTreeMap<String, String>> = HashMap::new();
+        let mut hashes = stream::iter(stored)
+            .map(|StoredAvatar { key, version }| async move {
+                let known = self.versions.get(&key).filter(|known| known.0 == version).map(|known| known.1.clone());
+                let hash = match known {
+                    Some(hash) => Ok(Some(hash)),
+                    None => self.store.hash(&key).await,
+                };
+                (key, version, hash)
+            })
+            .buffer_unordered(INDEX_CONCURRENCY);
+        while let Some((key, version, hash)) = hashes.next().await {
+            match hash {
+                Ok(Some(hash)) => {
+                    self.versions.insert(key.clone(), (version, hash.clone()));
+                    actual.entry(key.owner).or_default().insert(key.id, hash);
+                },
+                Ok(None) => {}, // Removed while indexing
+                Err(e) => tracing::warn!("Can't index avatar {key} due: {e:?}"),
+            }
+        }
+        self.versions.retain(|key, _| listed.contains(key));
+
+        let count = self.merge_index(started, actual);
+        tracing::debug!("Avatar index refreshed in {:?}", start.elapsed());
+        Ok(count)
+    }
+
+    /// Replaces the index with the listing made after `started`, except for owners changed since then
+    fn merge_index(&self, started: u64, actual: HashMap<Uuid, BTreeMap<String, String>>) -> usize {
+        // Changes are recorded under the owner's entry lock, so checking under the same lock can't miss them
+        let changed = |owner: &Uuid| self.modified.get(owner).is_some_and(|generation| *generation > started);
+        let count = actual.values().map(BTreeMap::len).sum();
+        self.hashes.retain(|owner, _| actual.contains_key(owner) || changed(owner));
+        for (owner, slots) in actual {
+            let entry = self.hashes.entry(owner);
+            if !changed(&owner) {
+                entry.insert(slots);
+            }
+        }
+        // Older changes matter only to refreshes that are already finished
+        self.modified.retain(|_, generation| *generation > started);
+        count
+    }
+
+    /// Figura-compatible hash of the avatar, `None` if there is no such avatar.
+    pub fn hash(&self, key: &AvatarKey) -> Option<String> {
+        self.hashes.get(&key.owner)?.get(&key.id).cloned()
+    }
+
+    /// All avatars of the user as <Slot id, Hash>
+    pub fn owned(&self, owner: &Uuid) -> BTreeMap<String, String> {
+        self.hashes.get(owner).map(|slots| slots.clone()).unwrap_or_default()
+    }
+
+    pub async fn get(&self, key: &AvatarKey) -> anyhow::Result<Option<Bytes>> {
+        self.store.get(key).await
+    }
+
+    pub async fn open(&self, key: &AvatarKey) -> anyhow::Result<Option<AvatarStream>> {
+        self.store.open(key).await
+    }
+
+    /// Creates or replaces the avatar. Returns `false` without storing anything if it would take a new slot beyond `max_avatars`:
+    /// the slot is reserved under the owner's index entry before writing, so parallel uploads can't overshoot the limit.
+    pub async fn put(&self, key: &AvatarKey, data: Bytes, max_avatars: Option<u64>) -> anyhow::Result<bool> {
+        let hash = calculate_avatar_hash(&data);
+        let Some(_reservation) = self.reserve(key, max_avatars) else {
+            return Ok(false);
+        };
+        self.store.put(key, data).await?;
+        let mut slots = self.hashes.entry(key.owner).or_default();
+        self.touch(&key.owner);
+        slots.insert(key.id.clone(), hash);
+        Ok(true)
+    }
+
+    /// Takes the slot unless it's a new one beyond `max_avatars`. The slot is released when the reservation is dropped.
+    fn reserve<'a>(&'a self, key: &'a AvatarKey, max_avatars: Option<u64>) -> Option<Reservation<'a>> {
+        // Reservations are changed only under the owner's index entry, so the count can't go stale until the slot is taken
+        let entry = self.hashes.entry(key.owner);
+        if let Some(max) = max_avatars {
+            let stored = match &entry {
+                Entry::Occupied(slots) => Some(slots.get()),
+                Entry::Vacant(_) => None,
+            };
+            let is_stored = |id: &String| stored.is_some_and(|slots| slots.contains_key(id));
+            let reserved = self.reserved.get(&key.owner);
+            let is_reserved = reserved.as_ref().is_some_and(|reserved| reserved.contains_key(&key.id));
+            let taken = stored.map_or(0, BTreeMap::len)
+                + reserved.as_ref().map_or(0, |reserved| reserved.keys().filter(|id| !is_stored(id)).count());
+            if !is_stored(&key.id) && !is_reserved && taken as u64 >= max {
+                return None;
+            }
+        }
+        *self.reserved.entry(key.owner).or_default().entry(key.id.clone()).or_default() += 1;
+        Some(Reservation { avatars: self, key })
+    }
+
+    pub async fn delete(&self, key: &AvatarKey) -> anyhow::Result<bool> {
+        let existed = self.store.delete(key).await?;
+        let entry = self.hashes.entry(key.owner);
+        self.touch(&key.owner);
+        if let Entry::Occupied(mut slots) = entry {
+            slots.get_mut().remove(&key.id);
+            if slots.get().is_empty() {
+                slots.remove();
+            }
+        }
+        Ok(existed)
+    }
+
+    /// Marks the owner as changed, must be called under the owner's entry in `hashes`
+    fn touch(&self, owner: &Uuid) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.modified.insert(*owner, generation);
+    }
+}
+
+/// Slot taken by an upload in progress, released even if the upload fails or is cancelled
+struct Reservation<'a> {
+    avatars: &'a Avatars,
+    key: &'a AvatarKey,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let _entry = self.avatars.hashes.entry(self.key.owner);
+        if let Entry::Occupied(mut reserved) = self.avatars.reserved.entry(self.key.owner) {
+            if let Some(uploads) = reserved.get_mut().get_mut(&self.key.id) {
+                *uploads -= 1;
+                if *uploads == 0 {
+                    reserved.get_mut().remove(&self.key.id);
+                }
+            }
+            if reserved.get().is_empty() {
+                reserved.remove();
+            }
+        }
+    }
+}
+
+/// Shared backends can be changed by other nodes, so their index has to be refreshed periodically.
+pub async fn refresh_avatar_index(avatars: Arc<Avatars>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await; // The first tick completes immediately, index is already built
+    loop {
+        interval.tick().await;
+        if let Err(e) = avatars.refresh_index().await {
+            tracing::error!("Can't refresh avatar index due: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FsStore, DEFAULT_AVATAR_ID};
+
+    #[tokio::test]
+    async fn index_follows_uploads_and_deletes() {
+        let folder = std::env::temp_dir().join(format!("sculptor-avatars-{}", Uuid::from_u128(rand::random())));
+        std::fs::create_dir_all(&folder).unwrap();
+        let owner = Uuid::from_u128(1);
+        let (stored, uploaded) = (AvatarKey::new(owner, DEFAULT_AVATAR_ID), AvatarKey::new(owner, "second"));
+        std::fs::write(folder.join(format!("{owner}.moon")), b"stored").unwrap();
+
+        let avatars = Avatars::new(AvatarStorage::Fs(FsStore::new(&folder)));
+        assert_eq!(avatars.refresh_index().await.unwrap(), 1);
+        assert_eq!(avatars.hash(&stored), Some(calculate_avatar_hash(b"stored")));
+
+        assert!(avatars.put(&uploaded, Bytes::from_static(b"uploaded"), Some(2)).await.unwrap());
+        assert_eq!(avatars.hash(&uploaded), Some(calculate_avatar_hash(b"uploaded")));
+        assert_eq!(avatars.owned(&owner).len(), 2);
+
+        // Over the limit, but replacing is fine
+        let third = AvatarKey::new(owner, "third");
+        assert!(!avatars.put(&third, Bytes::from_static(b"third"), Some(2)).await.unwrap());
+        assert_eq!(avatars.hash(&third), None);
+        assert!(!folder.join(third.file_name()).exists());
+        // Stored by another node, the index doesn't know it yet. A rejected upload must leave it alone
+        std::fs::write(folder.join(third.file_name()), b"elsewhere").unwrap();
+        assert!(!avatars.put(&third, Bytes::from_static(b"third"), Some(2)).await.unwrap());
+        assert_eq!(std::fs::read(folder.join(third.file_name())).unwrap(), b"elsewhere");
+        std::fs::remove_file(folder.join(third.file_name())).unwrap();
+        assert!(avatars.put(&uploaded, Bytes::from_static(b"replaced"), Some(2)).await.unwrap());
+        assert_eq!(avatars.hash(&uploaded), Some(calculate_avatar_hash(b"replaced")));
+        assert!(avatars.delete(&stored).await.unwrap());
+        assert_eq!(avatars.hash(&stored), None);
+        assert!(avatars.delete(&uploaded).await.unwrap());
+        assert!(avatars.owned(&owner).is_empty());
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn reservations_count_against_the_limit() {
+        let avatars = Avatars::new(AvatarStorage::Fs(FsStore::new(std::env::temp_dir())));
+        let owner = Uuid::from_u128(1);
+        let (first, second) = (AvatarKey::new(owner, "first"), AvatarKey::new(owner, "second"));
+
+        let uploading = avatars.reserve(&first, Some(1)).unwrap();
+        assert!(avatars.reserve(&second, Some(1)).is_none());
+        // Another upload to the same slot replaces it
+        let replacing = avatars.reserve(&first, Some(1)).unwrap();
+        drop(uploading);
+        assert!(avatars.reserve(&second, Some(1)).is_none());
+        // Failed and cancelled uploads free the slot
+        drop(replacing);
+        assert!(avatars.reserved.is_empty());
+        assert!(avatars.hashes.is_empty());
+        assert!(avatars.reserve(&second, Some(1)).is_some());
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_concurrent_changes() {
+        let folder = std::env::temp_dir().join(format!("sculptor-avatars-{}", Uuid::from_u128(rand::random())));
+        std::fs::create_dir_all(&folder).unwrap();
+        let (owner, other) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        let (uploaded, removed) = (AvatarKey::new(owner, DEFAULT_AVATAR_ID), AvatarKey::new(other, DEFAULT_AVATAR_ID));
+        let avatars = Avatars::new(AvatarStorage::Fs(FsStore::new(&folder)));
+        avatars.put(&removed, Bytes::from_static(b"removed"), None).await.unwrap();
+
+        // Listing started before the upload and the removal
+        let started = avatars.generation.load(Ordering::SeqCst);
+        let stale = HashMap::from([(other, BTreeMap::from([(removed.id.clone(), calculate_avatar_hash(b"removed"))]))]);
+        avatars.put(&uploaded, Bytes::from_static(b"uploaded"), None).await.unwrap();
+        assert!(avatars.delete(&removed).await.unwrap());
+
+        avatars.merge_index(started, stale);
+        assert_eq!(avatars.hash(&uploaded), Some(calculate_avatar_hash(b"uploaded")));
+        assert_eq!(avatars.hash(&removed), None);
+
+        // The next refresh sees everything
+        assert_eq!(avatars.refresh_index().await.unwrap(), 1);
+        assert!(avatars.modified.is_empty());
+        assert_eq!(avatars.hash(&uploaded), Some(calculate_avatar_hash(b"uploaded")));
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
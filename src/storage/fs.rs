@@ -0,0 +1,82 @@
+use std::{io::ErrorKind, path::PathBuf, time::UNIX_EPOCH};
+
+use axum::body::{Body, Bytes};
+use tokio::{fs, io::AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use super::{AvatarKey, AvatarStore, AvatarStream, StoredAvatar};
+
+/// Keeps avatars as `.moon` files in a local folder.
+#[derive(Debug)]
+pub struct FsStore {
+    folder: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(folder: impl Into<PathBuf>) -> Self {
+        Self { folder: folder.into() }
+    }
+
+    fn path(&self, key: &AvatarKey) -> PathBuf {
+        self.folder.join(key.file_name())
+    }
+}
+
+impl AvatarStore for FsStore {
+    async fn get(&self, key: &AvatarKey) -> anyhow::Result<Option<Bytes>> {
+        match fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn open(&self, key: &AvatarKey) -> anyhow::Result<Option<AvatarStream>> {
+        let file = match fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata().await?.len();
+        Ok(Some(AvatarStream { len, body: Body::from_stream(ReaderStream::new(file)) }))
+    }
+
+    /// Writes into a temporary file and renames it over the old avatar,
+    /// so a failed upload never leaves a truncated `.moon` behind.
+    async fn put(&self, key: &AvatarKey, data: Bytes) -> anyhow::Result<()> {
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("moon.{:016x}.tmp", rand::random::<u64>()));
+        let result = async {
+            let mut file = fs::File::create(&tmp).await?;
+            file.write_all(&data).await?;
+            file.sync_all().await?;
+            fs::rename(&tmp, &path).await
+        }.await;
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp).await;
+        }
+        Ok(result?)
+    }
+
+    async fn delete(&self, key: &AvatarKey) -> anyhow::Result<bool> {
+        match fs::remove_file(self.path(key)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self) -> anyhow::Result<Vec<StoredAvatar>> {
+        let mut avatars = Vec::new();
+        let mut entries = fs::read_dir(&self.folder).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(key) = entry.file_name().to_str().and_then(AvatarKey::from_file_name) {
+                // Avatars are replaced by renaming, so a new file always gets a new modification time
+                let metadata = entry.metadata().await?;
+                let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+                avatars.push(StoredAvatar { key, version: format!("{}-{}", modified.as_nanos(), metadata.len()) });
+            }
+        }
+        Ok(avatars)
+    }
+}
@@ -0,0 +1,180 @@
+use axum::body::{Body, Bytes};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::utils::calculate_avatar_hash;
+
+mod avatars;
+mod fs;
+mod s3;
+
+pub use avatars::*;
+pub use fs::FsStore;
+pub use s3::{S3Config, S3Store};
+
+/// Slot used by Figura clients which don't know about multiple avatars
+pub const DEFAULT_AVATAR_ID: &str = "avatar";
+
+/// Avatar slot of a user
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AvatarKey {
+    pub owner: Uuid,
+    pub id: String,
+}
+
+impl AvatarKey {
+    pub fn new(owner: Uuid, id: impl Into<String>) -> Self {
+        Self { owner, id: id.into() }
+    }
+
+    /// Slot ids become part of file names, so only `[A-Za-z0-9_-]{1,32}` is allowed
+    pub fn is_valid_id(id: &str) -> bool {
+        (1..=32).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    }
+
+    /// `<uuid>.moon` for the default slot (compatible with single-avatar storages), `<uuid>.<id>.moon` otherwise
+    pub fn file_name(&self) -> String {
+        if self.id == DEFAULT_AVATAR_ID {
+            format!("{}.moon", self.owner.as_hyphenated())
+        } else {
+            format!("{}.{}.moon", self.owner.as_hyphenated(), self.id)
+        }
+    }
+
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let stem = name.strip_suffix(".moon")?;
+        let (owner, id) = match stem.split_once('.') {
+            Some((owner, id)) if Self::is_valid_id(id) => (owner, id),
+            Some(_) => return None,
+            None => (stem, DEFAULT_AVATAR_ID),
+        };
+        Some(Self::new(Uuid::parse_str(owner).ok()?, id))
+    }
+}
+
+impl std::fmt::Display for AvatarKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.owner, self.id)
+    }
+}
+
+/// Avatar found in the backend listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredAvatar {
+    pub key: AvatarKey,
+    /// Changes whenever the avatar is replaced, so unchanged avatars don't have to be hashed again
+    pub version: String,
+}
+
+/// Avatar opened for streaming
+pub struct AvatarStream {
+    pub len: u64,
+    pub body: Body,
+}
+
+/// Backend which keeps `.moon` avatar files.
+pub trait AvatarStore {
+    /// Returns the avatar or `None` if it doesn't exist.
+    async fn get(&self, key: &AvatarKey) -> anyhow::Result<Option<Bytes>>;
+    /// Same as `get`, but the avatar is streamed instead of loaded into memory.
+    async fn open(&self, key: &AvatarKey) -> anyhow::Result<Option<AvatarStream>> {
+        Ok(self.get(key).await?.map(|data| AvatarStream { len: data.len() as u64, body: Body::from(data) }))
+    }
+    /// Creates or replaces the avatar.
+    async fn put(&self, key: &AvatarKey, data: Bytes) -> anyhow::Result<()>;
+    /// Removes the avatar. Returns `false` if there was nothing to remove.
+    async fn delete(&self, key: &AvatarKey) -> anyhow::Result<bool>;
+    /// Figura-compatible hash of the avatar.
+    async fn hash(&self, key: &AvatarKey) -> anyhow::Result<Option<String>> {
+        Ok(self.get(key).await?.map(|data| calculate_avatar_hash(&data)))
+    }
+    /// All stored avatars with their versions.
+    async fn list(&self) -> anyhow::Result<Vec<StoredAvatar>>;
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    /// Local folder (AVATARS_FOLDER)
+    #[default]
+    Fs,
+    /// S3-compatible object storage (AWS, MinIO, Garage, etc.)
+    S3(S3Config),
+}
+
+/// Storage backend selected in the config.
+#[derive(Debug)]
+pub enum AvatarStorage {
+    Fs(FsStore),
+    S3(Box<S3Store>),
+}
+
+impl AvatarStorage {
+    pub fn new(config: &StorageConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            StorageConfig::Fs => Self::Fs(FsStore::new(&*crate::AVATARS_VAR)),
+            StorageConfig::S3(config) => {
+                tracing::info!("Using S3 avatar storage: {} (bucket: {})", config.endpoint, config.bucket);
+                Self::S3(Box::new(S3Store::new(config.clone())?))
+            },
+        })
+    }
+}
+
+impl AvatarStore for AvatarStorage {
+    async fn get(&self, key: &AvatarKey) -> anyhow::Result<Option<Bytes>> {
+        match self {
+            Self::Fs(store) => store.get(key).await,
+            Self::S3(store) => store.get(key).await,
+        }
+    }
+    async fn open(&self, key: &AvatarKey) -> anyhow::Result<Option<AvatarStream>> {
+        match self {
+            Self::Fs(store) => store.open(key).await,
+            Self::S3(store) => store.open(key).await,
+        }
+    }
+    async fn put(&self, key: &AvatarKey, data: Bytes) -> anyhow::Result<()> {
+        match self {
+            Self::Fs(store) => store.put(key, data).await,
+            Self::S3(store) => store.put(key, data).await,
+        }
+    }
+    async fn delete(&self, key: &AvatarKey) -> anyhow::Result<bool> {
+        match self {
+            Self::Fs(store) => store.delete(key).await,
+            Self::S3(store) => store.delete(key).await,
+        }
+    }
+    async fn hash(&self, key: &AvatarKey) -> anyhow::Result<Option<String>> {
+        match self {
+            Self::Fs(store) => store.hash(key).await,
+            Self::S3(store) => store.hash(key).await,
+        }
+    }
+    async fn list(&self) -> anyhow::Result<Vec<StoredAvatar>> {
+        match self {
+            Self::Fs(store) => store.list().await,
+            Self::S3(store) => store.list().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn avatar_key_file_names() {
+        let owner = Uuid::from_u128(1);
+        for id in [DEFAULT_AVATAR_ID, "second", "my_avatar-2"] {
+            let key = AvatarKey::new(owner, id);
+            assert_eq!(AvatarKey::from_file_name(&key.file_name()), Some(key));
+        }
+        assert_eq!(AvatarKey::new(owner, DEFAULT_AVATAR_ID).file_name(), format!("{owner}.moon"));
+        assert_eq!(AvatarKey::from_file_name(&format!("{owner}.moon.0123456789abcdef.tmp")), None);
+        assert_eq!(AvatarKey::from_file_name(&format!("{owner}.a.b.moon")), None);
+        assert!(!AvatarKey::is_valid_id("../x"));
+        assert!(!AvatarKey::is_valid_id(""));
+    }
+}
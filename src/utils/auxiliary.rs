@@ -1,14 +1,16 @@
-use std::{fs::File, io::Read, path::{Path, PathBuf}, sync::Arc};
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::Arc};
 
 use notify::{Event, Watcher};
-use tokio::{io::AsyncReadExt, sync::RwLock};
+use tokio::sync::RwLock;
 use base64::prelude::*;
 use rand::{rng, Rng};
 use ring::digest::{self, digest};
 use uuid::Uuid;
 use chrono::prelude::*;
 
-use crate::{auth::Userinfo, state::{BannedPlayer, Config}, UManager};
+use crate::{auth::{IpBans, Userinfo}, state::{BannedIp, BannedPlayer, Config, ListedPlayer, MinecraftLists}, UManager};
+
+use super::JsonWatcher;
 
 pub fn rand() -> [u8; 50] {
     let mut rng = rng();
@@ -23,7 +25,7 @@ pub fn rand() -> [u8; 50] {
 pub async fn update_advanced_users(
     path: PathBuf,
     umanager: Arc<UManager>,
-    sessions: Arc<dashmap::DashMap<Uuid, tokio::sync::mpsc::Sender<crate::api::figura::SessionMessage>>>,
+    sessions: Arc<crate::api::figura::Sessions>,
     config: Arc<RwLock<Config>>,
 ) {
     let (tx, mut rx) = tokio::sync::mpsc::channel::<notify::Result<Event>>(1);
@@ -42,7 +44,10 @@ pub async fn update_advanced_users(
         let mut config = config.write().await;
 
         if new_config != *config || first_time {
-            if !first_time { tracing::info!("Server configuration modification detected!") }
+            if !first_time {
+                tracing::info!("Server configuration modification detected!");
+                new_config.warn_misconfiguration();
+            }
             first_time = false;
             *config = new_config;
             let users: Vec<(Uuid, Userinfo)> = config.advanced_users
@@ -63,7 +68,7 @@ pub async fn update_advanced_users(
                 umanager.insert_user(uuid, userinfo.clone());
                 if userinfo.banned {
                     umanager.ban(&userinfo);
-                    if let Some(tx) = sessions.get(&uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+                    sessions.send(&uuid, crate::api::figura::SessionMessage::Banned);
                 } else {
                     umanager.unban(&uuid);
                 }
@@ -75,17 +80,15 @@ pub async fn update_advanced_users(
 pub async fn update_bans_from_minecraft(
     folder: PathBuf,
     umanager: Arc<UManager>,
-    sessions: Arc<dashmap::DashMap<Uuid, tokio::sync::mpsc::Sender<crate::api::figura::SessionMessage>>>
+    sessions: Arc<crate::api::figura::Sessions>
 ) {
-    let path = folder.join("banned-players.json");
-    let mut file = tokio::fs::File::open(path.clone()).await.expect("Access denied or banned-players.json doesn't exists!");
-    let mut data = String::new();
-    // vars end
+    let mut watcher = match JsonWatcher::<Vec<BannedPlayer>>::new(folder.join("banned-players.json")) {
+        Ok(watcher) => watcher,
+        Err(e) => return tracing::error!("Can't watch banned-players.json due: {e}"),
+    };
 
     // initialize
-    file.read_to_string(&mut data).await.expect("cant read banned-players.json");
-    let mut old_bans: Vec<BannedPlayer> = serde_json::from_str(&data).expect("cant parse banned-players.json");
-
+    let Some(mut old_bans) = watcher.next().await else { return };
     if !old_bans.is_empty() {
         let names: Vec<String> = old_bans.iter().map(|user| user.name.clone()).collect();
         tracing::info!("Banned players: {}", names.join(", "));
@@ -93,48 +96,104 @@ pub async fn update_bans_from_minecraft(
 
     for player in &old_bans {
         umanager.ban(&player.clone().into());
-        if let Some(tx) = sessions.get(&player.uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
+        sessions.send(&player.uuid, crate::api::figura::SessionMessage::Banned);
     }
 
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<notify::Result<Event>>(1);
-    let mut watcher = notify::PollWatcher::new(
-        move |res| {
-            tx.blocking_send(res).unwrap();
-        },
-        notify::Config::default(),
-    ).unwrap();
-    watcher.watch(&path, notify::RecursiveMode::NonRecursive).unwrap();
-
     // old_bans
-    while rx.recv().await.is_some() {
-        let mut file = tokio::fs::File::open(path.clone()).await.expect("Access denied or file doesn't exists!");
-        let mut data = String::new();
-        file.read_to_string(&mut data).await.expect("cant read banned-players.json");
-        let new_bans: Vec<BannedPlayer> = if let Ok(res) = serde_json::from_str(&data) { res } else {
-            tracing::error!("Error occured while parsing a banned-players.json");
-            continue;
-        };
-
-        if new_bans != old_bans {
-            tracing::info!("Minecraft ban list modification detected!");
-            let unban: Vec<&BannedPlayer> = old_bans.iter().filter(|user| !new_bans.contains(user)).collect();
-            let mut unban_names = unban.iter().map(|user| user.name.clone()).collect::<Vec<String>>().join(", ");
-            if !unban.is_empty() {
-                for player in unban {
-                    umanager.unban(&player.uuid);
-                }
-            } else { unban_names = String::from("-")};
-            let ban: Vec<&BannedPlayer> = new_bans.iter().filter(|user| !old_bans.contains(user)).collect();
-            let mut ban_names = ban.iter().map(|user| user.name.clone()).collect::<Vec<String>>().join(", ");
-            if !ban.is_empty() {
-                for player in ban {
-                    umanager.ban(&player.clone().into());
-                    if let Some(tx) = sessions.get(&player.uuid) {let _ = tx.send(crate::api::figura::SessionMessage::Banned).await;}
-                }
-            } else { ban_names = String::from("-")};
-            tracing::info!("List of changes:\n    Banned: {ban_names}\n    Unbanned: {unban_names}");
-            // Write new to old for next iteration
-            old_bans = new_bans;
+    while let Some(new_bans) = watcher.next().await {
+        tracing::info!("Minecraft ban list modification detected!");
+        let unban: Vec<&BannedPlayer> = old_bans.iter().filter(|user| !new_bans.contains(user)).collect();
+        let mut unban_names = unban.iter().map(|user| user.name.clone()).collect::<Vec<String>>().join(", ");
+        if !unban.is_empty() {
+            for player in unban {
+                umanager.unban(&player.uuid);
+            }
+        } else { unban_names = String::from("-")};
+        let ban: Vec<&BannedPlayer> = new_bans.iter().filter(|user| !old_bans.contains(user)).collect();
+        let mut ban_names = ban.iter().map(|user| user.name.clone()).collect::<Vec<String>>().join(", ");
+        if !ban.is_empty() {
+            for player in ban {
+                umanager.ban(&player.clone().into());
+                sessions.send(&player.uuid, crate::api::figura::SessionMessage::Banned);
+            }
+        } else { ban_names = String::from("-")};
+        tracing::info!("List of changes:\n    Banned: {ban_names}\n    Unbanned: {unban_names}");
+        // Write new to old for next iteration
+        old_bans = new_bans;
+    }
+}
+
+pub async fn update_ip_bans_from_minecraft(folder: PathBuf, ip_bans: Arc<IpBans>) {
+    let mut watcher = match JsonWatcher::<Vec<BannedIp>>::new(folder.join("banned-ips.json")) {
+        Ok(watcher) => watcher,
+        Err(e) => return tracing::error!("Can't watch banned-ips.json due: {e}"),
+    };
+    while let Some(banned) = watcher.next().await {
+        tracing::info!("Minecraft IP ban list loaded: {} addresses", banned.len());
+        ip_bans.set_minecraft(banned.iter().map(|entry| (entry.ip, entry.expires_at())).collect());
+    }
+}
+
+pub async fn update_whitelist_from_minecraft(
+    folder: PathBuf,
+    umanager: Arc<UManager>,
+    sessions: Arc<crate::api::figura::Sessions>,
+    lists: Arc<MinecraftLists>,
+    config: Arc<RwLock<Config>>,
+) {
+    let mut watcher = match JsonWatcher::<Vec<ListedPlayer>>::new(folder.join("whitelist.json")) {
+        Ok(watcher) => watcher,
+        Err(e) => return tracing::error!("Can't watch whitelist.json due: {e}"),
+    };
+    while let Some(players) = watcher.next().await {
+        tracing::info!("Minecraft whitelist loaded: {} players", players.len());
+        let whitelist: HashSet<Uuid> = players.iter().map(|player| player.uuid).collect();
+        lists.set_whitelist(whitelist.clone());
+        if config.read().await.mc_whitelist {
+            let kicked = kick_unlisted(&umanager, &sessions, &whitelist);
+            if !kicked.is_empty() {
+                let names: Vec<String> = kicked.iter()
+                    .map(|uuid| umanager.get_by_uuid(uuid).map_or_else(|| uuid.to_string(), |user| user.nickname.clone()))
+                    .collect();
+                tracing::info!("Kicked players removed from the whitelist: {}", names.join(", "));
+            }
+        }
+    }
+}
+
+/// Revokes tokens and closes connections of everyone who isn't whitelisted, returns who was kicked
+fn kick_unlisted(umanager: &UManager, sessions: &crate::api::figura::Sessions, whitelist: &HashSet<Uuid>) -> Vec<Uuid> {
+    let kicked: Vec<Uuid> = umanager.authenticated_users().into_iter().filter(|uuid| !whitelist.contains(uuid)).collect();
+    for uuid in &kicked {
+        umanager.remove(uuid);
+        sessions.send(uuid, crate::api::figura::SessionMessage::Kicked);
+    }
+    kicked
+}
+
+pub async fn update_ops_from_minecraft(
+    folder: PathBuf,
+    umanager: Arc<UManager>,
+    lists: Arc<MinecraftLists>,
+    config: Arc<RwLock<Config>>,
+) {
+    let mut watcher = match JsonWatcher::<Vec<ListedPlayer>>::new(folder.join("ops.json")) {
+        Ok(watcher) => watcher,
+        Err(e) => return tracing::error!("Can't watch ops.json due: {e}"),
+    };
+    while let Some(players) = watcher.next().await {
+        let ops: HashSet<Uuid> = players.iter().map(|player| player.uuid).collect();
+        tracing::info!("Minecraft operators loaded: {}", players.iter().map(|player| player.name.as_str()).collect::<Vec<_>>().join(", "));
+        let old_ops = lists.set_ops(ops.clone());
+        let Some(rank) = config.read().await.mc_ops_rank.clone() else { continue };
+        for uuid in ops.difference(&old_ops) {
+            umanager.set_rank(uuid, &rank);
+        }
+        // Only the rank given by us is taken away
+        for uuid in old_ops.difference(&ops) {
+            if umanager.get_by_uuid(uuid).is_some_and(|user| user.rank == rank) {
+                umanager.set_rank(uuid, &Userinfo::default().rank);
+            }
         }
     }
 }
@@ -145,23 +204,27 @@ pub fn format_uuid(uuid: &Uuid) -> String {
     uuid.as_hyphenated().to_string()
 }
 
-pub fn calculate_file_sha256(file_path: &str) -> Result<String, std::io::Error> {
-    // Read the file content
-    let mut file = File::open(file_path)?;
-    let mut content = Vec::new();
-    file.read_to_end(&mut content)?;
-
+pub fn calculate_avatar_hash(content: &[u8]) -> String {
     // Convert the content to base64
-    let base64_content = BASE64_STANDARD.encode(&content);
+    let base64_content = BASE64_STANDARD.encode(content);
 
     // Calculate the SHA-256 hash of the base64 string
     let binding = digest(&digest::SHA256, base64_content.as_bytes());
     let hash = binding.as_ref();
 
     // Convert the hash to a hexadecimal string
-    let hex_hash = faster_hex::hex_string(hash);
+    faster_hex::hex_string(hash)
+}
 
-    Ok(hex_hash)
+/// Checks `If-None-Match` against the entity tag (quotes included). Weak comparison, as RFC 9110 requires.
+pub fn etag_matches(headers: &axum::http::HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(axum::http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
 }
 
 pub fn get_log_file(folder: &str) -> String {
@@ -177,6 +240,37 @@ pub fn get_log_file(folder: &str) -> String {
     }
 }
 
-pub fn get_limit_as_bytes(limit: usize) -> usize {
-    1024 + limit * 1024 // Adding additional 1 KB just for fun :)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::IF_NONE_MATCH, HeaderMap, HeaderValue};
+
+    #[tokio::test]
+    async fn whitelist_removal_kicks() {
+        use crate::api::figura::{SessionMessage, Sessions};
+        let umanager = UManager::new();
+        let sessions = Sessions::default();
+        let (listed, removed) = (Uuid::from_u128(1), Uuid::from_u128(2));
+        for uuid in [listed, removed] {
+            umanager.insert(uuid, uuid.to_string(), Userinfo { uuid, ..Default::default() }, std::time::Duration::from_secs(60));
+        }
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        sessions.insert(removed, tx);
+
+        assert_eq!(kick_unlisted(&umanager, &sessions, &HashSet::from([listed])), vec![removed]);
+        assert!(umanager.get(&listed.to_string()).is_some());
+        assert!(umanager.get(&removed.to_string()).is_none());
+        assert!(matches!(rx.recv().await, Some(SessionMessage::Kicked)));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let mut headers = HeaderMap::new();
+        assert!(!etag_matches(&headers, "\"abc\""));
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"xyz\", W/\"abc\""));
+        assert!(etag_matches(&headers, "\"abc\""));
+        assert!(!etag_matches(&headers, "\"ab\""));
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert!(etag_matches(&headers, "\"anything\""));
+    }
+}
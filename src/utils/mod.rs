@@ -1,7 +1,11 @@
 mod auxiliary;
 mod check_updates;
 mod motd;
+mod watcher;
+mod token_bucket;
 
 pub use auxiliary::*;
 pub use motd::*;
-pub use check_updates::*;
\ No newline at end of file
+pub use check_updates::*;
+pub use watcher::*;
+pub use token_bucket::*;
\ No newline at end of file
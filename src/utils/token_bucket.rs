@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+/// Classic token bucket: holds up to `capacity` tokens and gets `rate` new ones every second
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full
+    pub fn new(capacity: f64, rate: f64) -> Self {
+        Self { capacity, rate, tokens: capacity, updated: Instant::now() }
+    }
+
+    /// Tokens available at `now`
+    pub fn available(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+        self.tokens
+    }
+
+    /// Takes `amount` tokens if there are enough of them
+    pub fn try_take(&mut self, amount: f64, now: Instant) -> bool {
+        if self.available(now) < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = TokenBucket::new(4.0, 2.0);
+        let start = bucket.updated;
+        assert!(bucket.try_take(3.0, start));
+        assert!(!bucket.try_take(2.0, start));
+        assert!(bucket.try_take(2.0, start + Duration::from_millis(500)));
+        // Never grows above the capacity
+        assert_eq!(bucket.available(start + Duration::from_secs(60)), 4.0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{path::PathBuf, time::Duration};
+
+use notify::{Event, PollWatcher, Watcher};
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+/// How often watched files are checked
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Follows a JSON file (like Minecraft's lists) and yields its content every time it changes.
+/// The file may not exist yet, it's picked up once created.
+pub struct JsonWatcher<T> {
+    path: PathBuf,
+    interval: Duration,
+    rx: mpsc::Receiver<notify::Result<Event>>,
+    last: Option<T>,
+    watcher: PollWatcher,
+    /// Polling can't start until the file exists
+    watching: bool,
+}
+
+impl<T: DeserializeOwned + PartialEq + Clone> JsonWatcher<T> {
+    pub fn new(path: PathBuf) -> notify::Result<Self> {
+        Self::with_interval(path, POLL_INTERVAL)
+    }
+
+    pub fn with_interval(path: PathBuf, interval: Duration) -> notify::Result<Self> {
+        let (tx, rx) = mpsc::channel(1);
+        let watcher = PollWatcher::new(
+            move |res| {
+                // One queued event is enough to re-read the file
+                let _ = tx.try_send(res);
+            },
+            notify::Config::default().with_poll_interval(interval),
+        )?;
+        Ok(Self { path, interval, rx, last: None, watcher, watching: false })
+    }
+
+    /// Waits for a version of the file that differs from the previous one. Broken versions are skipped.
+    /// The current content is returned by the first call.
+    pub async fn next(&mut self) -> Option<T> {
+        loop {
+            if !self.watching {
+                self.wait_for_file().await;
+            } else if self.rx.recv().await.is_none() {
+                return None;
+            }
+            let data = match tokio::fs::read_to_string(&self.path).await {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::error!("Can't read {} due: {e}", self.path.display());
+                    continue;
+                },
+            };
+            let parsed: T = match serde_json::from_str(&data) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    tracing::error!("Error occured while parsing a {} due: {e}", self.path.display());
+                    continue;
+                },
+            };
+            if self.last.as_ref() != Some(&parsed) {
+                self.last = Some(parsed.clone());
+                return Some(parsed);
+            }
+        }
+    }
+
+    /// Checks for the file every interval and starts watching it once it appears
+    async fn wait_for_file(&mut self) {
+        let mut reported = false;
+        loop {
+            if tokio::fs::try_exists(&self.path).await.unwrap_or(false) {
+                match self.watcher.watch(&self.path, notify::RecursiveMode::NonRecursive) {
+                    Ok(()) => {
+                        self.watching = true;
+                        return;
+                    },
+                    Err(e) => tracing::error!("Can't watch {} due: {e}", self.path.display()),
+                }
+            } else if !reported {
+                tracing::debug!("{} doesn't exist yet, waiting for it", self.path.display());
+                reported = true;
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn yields_current_content() {
+        let path = std::env::temp_dir().join(format!("sculptor-watcher-{}.json", uuid::Uuid::from_u128(rand::random())));
+        std::fs::write(&path, "[1, 2]").unwrap();
+
+        let mut watcher = JsonWatcher::<Vec<u8>>::new(path.clone()).unwrap();
+        assert_eq!(watcher.next().await, Some(vec![1, 2]));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn waits_for_missing_file() {
+        let folder = std::env::temp_dir().join(format!("sculptor-watcher-{}", uuid::Uuid::from_u128(rand::random())));
+        let path = folder.join("list.json");
+
+        let mut watcher = JsonWatcher::<Vec<u8>>::with_interval(path.clone(), Duration::from_millis(20)).unwrap();
+        let next = tokio::spawn(async move {
+            let first = watcher.next().await;
+            (first, watcher.next().await)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(&path, "[1]").unwrap();
+        // Modification time has a one second resolution on some file systems
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        std::fs::write(&path, "[1, 2]").unwrap();
+
+        let (first, second) = tokio::time::timeout(Duration::from_secs(10), next).await.unwrap().unwrap();
+        assert_eq!((first, second), (Some(vec![1]), Some(vec![1, 2])));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}